@@ -0,0 +1,95 @@
+use std::error::Error;
+
+use chrono::{DateTime, Utc};
+use twilight_http::Client as HttpClient;
+use twilight_model::channel::Message;
+use twilight_model::id::Id;
+use twilight_model::id::marker::{ChannelMarker, MessageMarker};
+
+/// The most messages discord will hand back for a single `channel_messages` request
+const MAX_MESSAGES_PER_REQUEST: u16 = 100;
+
+/// Where in a channel's history to start walking from
+#[derive(Debug, Clone, Copy)]
+pub enum HistoryCursor {
+    /// Start at the newest message and walk backwards in time
+    Latest,
+    /// Walk backwards in time starting just before the given message
+    Before(Id<MessageMarker>),
+    /// Walk forwards in time starting just after the given message
+    After(Id<MessageMarker>),
+}
+
+/// When to stop walking a channel's history, the default walks the whole channel
+#[derive(Debug, Clone, Default)]
+pub struct HistoryLimit {
+    /// Stop once this many messages have been collected
+    pub max_messages: Option<usize>,
+    /// Ignore any messages posted before this time
+    pub oldest_message_time: Option<DateTime<Utc>>,
+}
+
+impl HistoryLimit {
+    fn is_too_old(&self, message: &Message) -> bool {
+        match self.oldest_message_time {
+            Some(oldest_message_time) => message.timestamp.as_micros() < oldest_message_time.timestamp_micros(),
+            None => false,
+        }
+    }
+}
+
+/// Fetches every message of a channel from `cursor` until the end of the history (or `limit`) is reached.
+///
+/// Messages are returned newest first, the same order discord returns a single page in.
+pub async fn fetch_channel_history(
+    http: &HttpClient,
+    channel_id: Id<ChannelMarker>,
+    cursor: HistoryCursor,
+    limit: &HistoryLimit,
+) -> Result<Vec<Message>, Box<dyn Error + Send + Sync>> {
+    let mut messages = Vec::new();
+    let mut cursor = cursor;
+
+    loop {
+        let page_size = match limit.max_messages {
+            Some(max_messages) => (max_messages - messages.len()).min(MAX_MESSAGES_PER_REQUEST as usize) as u16,
+            None => MAX_MESSAGES_PER_REQUEST,
+        };
+        if page_size == 0 {
+            break;
+        }
+
+        let page = match cursor {
+            HistoryCursor::Latest => http.channel_messages(channel_id).limit(page_size)?.await?,
+            HistoryCursor::Before(message_id) => http.channel_messages(channel_id).before(message_id).limit(page_size)?.await?,
+            HistoryCursor::After(message_id) => http.channel_messages(channel_id).after(message_id).limit(page_size)?.await?,
+        }.model().await?;
+
+        let reached_end = page.len() < page_size as usize;
+
+        // Discord always returns a page newest first, regardless of the direction we are walking in
+        cursor = match cursor {
+            HistoryCursor::Latest | HistoryCursor::Before(_) => match page.last() {
+                Some(oldest) => HistoryCursor::Before(oldest.id),
+                None => break,
+            },
+            HistoryCursor::After(_) => match page.first() {
+                Some(newest) => HistoryCursor::After(newest.id),
+                None => break,
+            },
+        };
+
+        // When walking backwards, once one message is too old every message after it will be too
+        let walked_past_oldest_message_time = matches!(cursor, HistoryCursor::Before(_)) && page.iter().any(|message| limit.is_too_old(message));
+
+        messages.extend(page.into_iter().filter(|message| !limit.is_too_old(message)));
+
+        if reached_end || walked_past_oldest_message_time {
+            break;
+        }
+    }
+
+    messages.sort_unstable_by_key(|message| std::cmp::Reverse(message.id));
+
+    Ok(messages)
+}
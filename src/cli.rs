@@ -0,0 +1,174 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use clap::{Parser, value_parser};
+
+use crate::channel_history::HistoryLimit;
+use crate::gallery_build::{ChannelParseMode, DEFAULT_PICTURES_PER_PAGE, GalleryBuildOptions};
+use crate::prune::PruneMode;
+use crate::thumbnail_download::{DEFAULT_THUMBNAIL_WIDTHS, ThumbnailFormat};
+use crate::website::dates::DEFAULT_TIME_FORMAT;
+
+/// Builds a static photo gallery website from the channels of a discord guild category.
+///
+/// Any selection not given on the command line is asked for interactively.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Build the profiles from this TOML config file instead of the selections below
+    #[arg(long, conflicts_with_all = ["guild", "category"])]
+    pub config: Option<PathBuf>,
+
+    /// Name of a config file profile to build, may be repeated (default: every profile)
+    #[arg(long = "profile", requires = "config")]
+    pub profiles: Vec<String>,
+
+    /// Read guilds and messages from a directory of JSON fixtures instead of connecting to discord
+    #[arg(long)]
+    pub fixtures: Option<PathBuf>,
+
+    /// Read guilds and messages from DiscordChatExporter JSON exports (a file or a directory of them)
+    #[arg(long, conflicts_with = "fixtures")]
+    pub chat_export: Option<PathBuf>,
+
+    /// Keep running after the first build, rebuilding galleries whenever photos are posted, edited or deleted
+    #[arg(long, conflicts_with_all = ["fixtures", "chat_export"])]
+    pub watch: bool,
+
+    /// Seconds to wait for more changes before rebuilding a watched gallery
+    #[arg(long, default_value_t = 30, requires = "watch")]
+    pub debounce: u64,
+
+    /// Swap the website back to the build before the last one instead of building, rolling back again undoes it
+    #[arg(long, conflicts_with_all = ["watch", "fixtures", "chat_export"])]
+    pub rollback: bool,
+
+    /// Guild to build the gallery from, by name or ID
+    #[arg(short, long)]
+    pub guild: Option<String>,
+
+    /// Category within the guild whose channels become galleries, by name or ID
+    #[arg(short, long)]
+    pub category: Option<String>,
+
+    /// Directory the website is written to
+    #[arg(short, long, default_value = "website")]
+    pub output: PathBuf,
+
+    /// URL the output directory is published at, given out by the `/gallery link` command in watch mode
+    #[arg(long)]
+    pub public_url: Option<String>,
+
+    /// Folder of templates, partials and resources used in place of the built-in ones, anything it lacks is built-in
+    #[arg(long)]
+    pub theme: Option<PathBuf>,
+
+    /// Write the built-in theme into this folder as a starting point for `--theme`, instead of building
+    #[arg(long, exclusive = true)]
+    pub export_theme: Option<PathBuf>,
+
+    /// How gallery titles are parsed from channel names
+    #[arg(short, long, value_enum, default_value_t = ChannelParseMode::FirstFullLastInitial)]
+    pub parse_mode: ChannelParseMode,
+
+    /// Widths of the thumbnails made for every picture, browsers pick the best one for the screen
+    #[arg(long, value_delimiter = ',', value_parser = value_parser!(u32).range(1..), default_values_t = DEFAULT_THUMBNAIL_WIDTHS)]
+    pub thumbnail_widths: Vec<u32>,
+
+    /// Most pictures shown on one page of a gallery, the rest are split across further pages
+    #[arg(long, default_value_t = DEFAULT_PICTURES_PER_PAGE)]
+    pub pictures_per_page: usize,
+
+    /// Timezone the build time and the dates of pictures are shown in, such as `Europe/Berlin`
+    #[arg(long, default_value_t = Tz::UTC)]
+    pub timezone: Tz,
+
+    /// strftime style format the build time is shown in
+    #[arg(long, default_value = DEFAULT_TIME_FORMAT)]
+    pub time_format: String,
+
+    /// Format thumbnails are saved in, formats other than JPEG also get JPEG thumbnails for older browsers
+    #[arg(long, value_enum, default_value_t = ThumbnailFormat::Jpeg)]
+    pub thumbnail_format: ThumbnailFormat,
+
+    /// Download full size images into the website, as discord's links to them expire
+    #[arg(long)]
+    pub mirror_originals: bool,
+
+    /// Keep linking to discord for images larger than this many MiB when mirroring originals
+    #[arg(long, requires = "mirror_originals")]
+    pub max_original_size: Option<u64>,
+
+    /// Show camera and exposure details in the preview and keep them in mirrored originals, GPS and such are removed
+    #[arg(long)]
+    pub keep_camera_info: bool,
+
+    /// Show only the largest of each group of near-duplicate pictures in a gallery, such as edits and crops of one shot
+    #[arg(long)]
+    pub collapse_near_duplicates: bool,
+
+    /// What to do with downloaded thumbnails and originals the website no longer uses, like those of deleted messages
+    #[arg(long, value_enum, default_value_t = PruneMode::Keep)]
+    pub prune: PruneMode,
+
+    /// Publish the website even when downloads failed in a way a later build may fix, such as timeouts
+    #[arg(long)]
+    pub allow_failed_downloads: bool,
+
+    /// Only fetch messages newer than the last build, tracked in a sync state file in the output directory
+    #[arg(long)]
+    pub incremental: bool,
+
+    /// Refetch every channel's whole history to pick up edited and deleted messages in incremental builds
+    #[arg(long)]
+    pub full_resync: bool,
+
+    /// Only fetch up to this many of the newest messages per channel
+    #[arg(long)]
+    pub max_messages: Option<usize>,
+
+    /// Ignore messages posted before this date (`YYYY-MM-DD` or RFC 3339)
+    #[arg(long, value_parser = parse_date)]
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl Cli {
+    pub fn build_options(&self) -> GalleryBuildOptions {
+        let mut build_options = GalleryBuildOptions::new(self.output.clone());
+        build_options.public_url = self.public_url.clone();
+        build_options.channel_parse_mode = self.parse_mode;
+        build_options.thumbnail_widths = self.thumbnail_widths.clone();
+        build_options.thumbnail_format = self.thumbnail_format;
+        build_options.mirror_originals = self.mirror_originals;
+        build_options.keep_camera_info = self.keep_camera_info;
+        build_options.collapse_near_duplicates = self.collapse_near_duplicates;
+        build_options.prune_mode = self.prune;
+        build_options.allow_failed_downloads = self.allow_failed_downloads;
+        build_options.theme_folder = self.theme.clone();
+        build_options.pictures_per_page = self.pictures_per_page;
+        build_options.timezone = self.timezone;
+        build_options.time_format = self.time_format.clone();
+        build_options.incremental = self.incremental;
+        build_options.full_resync = self.full_resync;
+        build_options.max_original_size = self.max_original_size.map(|max_original_size| max_original_size * BYTES_PER_MIB);
+        build_options.history_limit = HistoryLimit {
+            max_messages: self.max_messages,
+            oldest_message_time: self.since,
+        };
+
+        build_options
+    }
+}
+
+pub(crate) const BYTES_PER_MIB: u64 = 1024 * 1024;
+
+pub(crate) fn parse_date(date: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date_time) = DateTime::parse_from_rfc3339(date) {
+        return Ok(date_time.with_timezone(&Utc));
+    }
+
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .map_err(|_| format!("`{date}` is not a `YYYY-MM-DD` or RFC 3339 date"))
+}
@@ -0,0 +1,176 @@
+//! The `/gallery` slash command, letting moderators without access to the machine running the bot
+//! rebuild, check on and link to the galleries built from their guild.
+
+use twilight_http::Client as HttpClient;
+use twilight_model::application::command::{CommandOption, CommandOptionType};
+use twilight_model::application::interaction::{Interaction, InteractionData, InteractionType};
+use twilight_model::channel::message::MessageFlags;
+use twilight_model::guild::Permissions;
+use twilight_model::http::interaction::{InteractionResponse, InteractionResponseData, InteractionResponseType};
+use twilight_model::id::Id;
+use twilight_model::id::marker::{ApplicationMarker, GuildMarker};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::source::SourceResult;
+use crate::watch::WatchEvent;
+
+pub const GALLERY_COMMAND_NAME: &str = "gallery";
+
+/// Members need this permission to use the gallery command
+pub const GALLERY_COMMAND_PERMISSIONS: Permissions = Permissions::MANAGE_GUILD;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GalleryCommand {
+    /// Refetch every gallery channel and rebuild the website straight away
+    Rebuild,
+    /// Reply with when each gallery was last built and how many pictures it has
+    Status,
+    /// Reply with the URL each gallery is published at
+    Link,
+}
+
+impl GalleryCommand {
+    const ALL: [GalleryCommand; 3] = [GalleryCommand::Rebuild, GalleryCommand::Status, GalleryCommand::Link];
+
+    fn name(self) -> &'static str {
+        match self {
+            GalleryCommand::Rebuild => "rebuild",
+            GalleryCommand::Status => "status",
+            GalleryCommand::Link => "link",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            GalleryCommand::Rebuild => "Refetch every gallery channel and rebuild the photo galleries now",
+            GalleryCommand::Status => "Show when the photo galleries were last built",
+            GalleryCommand::Link => "Show where the photo galleries are published",
+        }
+    }
+}
+
+/// A gallery command that passed the permission check, already acknowledged so it can be answered later
+#[derive(Debug)]
+pub struct GalleryCommandRequest {
+    pub command: GalleryCommand,
+    pub guild_id: Id<GuildMarker>,
+    pub application_id: Id<ApplicationMarker>,
+    pub interaction_token: String,
+}
+
+impl GalleryCommandRequest {
+    /// Replaces the reply to the command, which starts out as discord's "thinking" message
+    pub async fn reply(&self, http: &HttpClient, content: &str) -> SourceResult<()> {
+        http.interaction(self.application_id)
+            .update_response(&self.interaction_token)
+            .content(Some(content))?
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Registers the gallery command in a guild, replacing the previous registration if there was one
+pub async fn register_gallery_command(http: &HttpClient, application_id: Id<ApplicationMarker>, guild_id: Id<GuildMarker>) -> SourceResult<()> {
+    let subcommands = GalleryCommand::ALL.map(|command| CommandOption {
+        autocomplete: None,
+        channel_types: None,
+        choices: None,
+        description: command.description().to_owned(),
+        description_localizations: None,
+        kind: CommandOptionType::SubCommand,
+        max_length: None,
+        max_value: None,
+        min_length: None,
+        min_value: None,
+        name: command.name().to_owned(),
+        name_localizations: None,
+        options: None,
+        required: None,
+    });
+
+    http.interaction(application_id)
+        .create_guild_command(guild_id)
+        .chat_input(GALLERY_COMMAND_NAME, "Manage this server's photo galleries")?
+        .default_member_permissions(GALLERY_COMMAND_PERMISSIONS)
+        .command_options(&subcommands)?
+        .await?;
+
+    Ok(())
+}
+
+/// Answers a gallery command from the gateway, passing it on to be run if the member is allowed to use it.
+///
+/// Discord only waits a few seconds for a first reply, so commands are acknowledged straight away and answered
+/// once they have run.
+pub async fn handle_interaction(http: &HttpClient, interaction: Interaction, watch_sender: &UnboundedSender<WatchEvent>) -> SourceResult<()> {
+    let Some(command) = parse_gallery_command(&interaction) else {
+        return Ok(());
+    };
+    let Some(guild_id) = interaction.guild_id else {
+        return respond(http, &interaction, "The gallery command can only be used in a server").await;
+    };
+
+    // Discord hides the command from members without the permission, but server admins can change that
+    let member_permissions = interaction.member.as_ref().and_then(|member| member.permissions).unwrap_or_else(Permissions::empty);
+    if !member_permissions.intersects(GALLERY_COMMAND_PERMISSIONS | Permissions::ADMINISTRATOR) {
+        return respond(http, &interaction, "You need the Manage Server permission to use the gallery command").await;
+    }
+
+    let deferred_response = InteractionResponse {
+        kind: InteractionResponseType::DeferredChannelMessageWithSource,
+        data: Some(InteractionResponseData {
+            flags: Some(MessageFlags::EPHEMERAL),
+            ..Default::default()
+        }),
+    };
+    http.interaction(interaction.application_id)
+        .create_response(interaction.id, &interaction.token, &deferred_response)
+        .await?;
+
+    let command_request = GalleryCommandRequest {
+        command,
+        guild_id,
+        application_id: interaction.application_id,
+        interaction_token: interaction.token,
+    };
+    // Outside of watch mode nothing is receiving commands
+    if watch_sender.is_closed() {
+        return command_request.reply(http, "The gallery bot isn't running in watch mode, so it can't run commands").await;
+    }
+    let _ = watch_sender.send(WatchEvent::GalleryCommand(command_request));
+
+    Ok(())
+}
+
+fn parse_gallery_command(interaction: &Interaction) -> Option<GalleryCommand> {
+    if interaction.kind != InteractionType::ApplicationCommand {
+        return None;
+    }
+    let Some(InteractionData::ApplicationCommand(command_data)) = &interaction.data else {
+        return None;
+    };
+    if command_data.name != GALLERY_COMMAND_NAME {
+        return None;
+    }
+
+    let subcommand_name = command_data.options.first()?.name.as_str();
+    GalleryCommand::ALL.into_iter().find(|command| command.name() == subcommand_name)
+}
+
+/// Replies to an interaction that hasn't been acknowledged yet, visible only to the member who used it
+async fn respond(http: &HttpClient, interaction: &Interaction, content: &str) -> SourceResult<()> {
+    let response = InteractionResponse {
+        kind: InteractionResponseType::ChannelMessageWithSource,
+        data: Some(InteractionResponseData {
+            content: Some(content.to_owned()),
+            flags: Some(MessageFlags::EPHEMERAL),
+            ..Default::default()
+        }),
+    };
+    http.interaction(interaction.application_id)
+        .create_response(interaction.id, &interaction.token, &response)
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize as _, Deserializer};
+use serde_derive::Deserialize;
+use twilight_model::id::Id;
+use twilight_model::id::marker::{ChannelMarker, GuildMarker};
+
+use crate::channel_history::HistoryLimit;
+use crate::cli::{BYTES_PER_MIB, parse_date};
+use crate::gallery_build::{ChannelFilter, ChannelParseMode, GalleryBuildOptions};
+use crate::prune::PruneMode;
+use crate::thumbnail_download::ThumbnailFormat;
+use crate::website::dates::check_time_format;
+
+/// A config file describing one or more named gallery builds, for example:
+///
+/// ```toml
+/// [profile.photo-club]
+/// guild = 123456789012345678
+/// category = 234567890123456789
+/// output = "photo_club_website"
+/// public_url = "https://photos.example.com/"
+/// page_title = "{{guild_name}} - {{category_name}}"
+/// gallery_title = "{{channel_author_name}}"
+/// parse_mode = "full-name"
+/// thumbnail_widths = [300, 600, 1200]
+/// thumbnail_format = "webp"
+/// mirror_originals = true
+/// max_original_size = 20
+/// keep_camera_info = true
+/// collapse_near_duplicates = true
+/// prune = "delete"
+/// allow_failed_downloads = true
+/// theme = "photo_club_theme"
+/// pictures_per_page = 60
+/// timezone = "Europe/Berlin"
+/// time_format = "%d.%m.%Y %H:%M"
+/// incremental = true
+/// since = "2023-01-01"
+///
+/// [profile.photo-club.filters]
+/// exclude_channels = ["announcements"]
+/// min_pictures = 5
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GalleryConfig {
+    #[serde(rename = "profile", default)]
+    pub profiles: BTreeMap<String, GalleryProfile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GalleryProfile {
+    pub guild: Id<GuildMarker>,
+    pub category: Id<ChannelMarker>,
+    pub output: PathBuf,
+    pub public_url: Option<String>,
+    pub page_title: Option<String>,
+    pub gallery_title: Option<String>,
+    pub parse_mode: Option<ChannelParseMode>,
+    pub thumbnail_widths: Option<Vec<u32>>,
+    pub thumbnail_format: Option<ThumbnailFormat>,
+    #[serde(default)]
+    pub mirror_originals: bool,
+    /// In MiB
+    pub max_original_size: Option<u64>,
+    #[serde(default)]
+    pub keep_camera_info: bool,
+    #[serde(default)]
+    pub collapse_near_duplicates: bool,
+    pub prune: Option<PruneMode>,
+    #[serde(default)]
+    pub allow_failed_downloads: bool,
+    pub theme: Option<PathBuf>,
+    pub pictures_per_page: Option<usize>,
+    pub timezone: Option<Tz>,
+    pub time_format: Option<String>,
+    #[serde(default)]
+    pub incremental: bool,
+    pub max_messages: Option<usize>,
+    #[serde(default, deserialize_with = "deserialize_date")]
+    pub since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub filters: ChannelFilter,
+}
+
+fn deserialize_date<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+    let date = String::deserialize(deserializer)?;
+    parse_date(&date).map(Some).map_err(serde::de::Error::custom)
+}
+
+impl GalleryConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<GalleryConfig, Box<dyn Error + Send + Sync>> {
+        let config_string = fs::read_to_string(path.as_ref())
+            .map_err(|err| format!("Failed to read config file `{}`: {}", path.as_ref().display(), err))?;
+        let config: GalleryConfig = toml::from_str(&config_string)
+            .map_err(|err| format!("Failed to parse config file `{}`: {}", path.as_ref().display(), err))?;
+        for (profile_name, profile) in &config.profiles {
+            let build_options = profile.build_options();
+            build_options.check_title_templates()
+                .and_then(|()| build_options.check_thumbnail_widths())
+                .and_then(|()| check_time_format(&build_options.time_format))
+                .map_err(|err| format!("Profile `{}` of config file `{}`: {}", profile_name, path.as_ref().display(), err))?;
+        }
+
+        Ok(config)
+    }
+
+    /// Returns the profiles with the given names, or every profile if no names are given
+    pub fn select_profiles(&self, profile_names: &[String]) -> Result<Vec<(&str, &GalleryProfile)>, Box<dyn Error + Send + Sync>> {
+        if profile_names.is_empty() {
+            return Ok(self.profiles.iter().map(|(name, profile)| (name.as_str(), profile)).collect());
+        }
+
+        profile_names
+            .iter()
+            .map(|profile_name| match self.profiles.get_key_value(profile_name) {
+                Some((name, profile)) => Ok((name.as_str(), profile)),
+                None => Err(format!("No profile named `{profile_name}` in config file").into()),
+            })
+            .collect()
+    }
+}
+
+impl GalleryProfile {
+    pub fn build_options(&self) -> GalleryBuildOptions {
+        let mut build_options = GalleryBuildOptions::new(self.output.clone());
+        build_options.public_url = self.public_url.clone();
+
+        if let Some(page_title) = &self.page_title {
+            build_options.page_title_template = page_title.clone();
+        }
+        if let Some(gallery_title) = &self.gallery_title {
+            build_options.gallery_title_template = gallery_title.clone();
+        }
+        if let Some(parse_mode) = self.parse_mode {
+            build_options.channel_parse_mode = parse_mode;
+        }
+        if let Some(thumbnail_widths) = &self.thumbnail_widths {
+            build_options.thumbnail_widths = thumbnail_widths.clone();
+        }
+        if let Some(thumbnail_format) = self.thumbnail_format {
+            build_options.thumbnail_format = thumbnail_format;
+        }
+        build_options.mirror_originals = self.mirror_originals;
+        build_options.max_original_size = self.max_original_size.map(|max_original_size| max_original_size * BYTES_PER_MIB);
+        build_options.keep_camera_info = self.keep_camera_info;
+        build_options.collapse_near_duplicates = self.collapse_near_duplicates;
+        if let Some(prune_mode) = self.prune {
+            build_options.prune_mode = prune_mode;
+        }
+        build_options.allow_failed_downloads = self.allow_failed_downloads;
+        build_options.theme_folder = self.theme.clone();
+        if let Some(pictures_per_page) = self.pictures_per_page {
+            build_options.pictures_per_page = pictures_per_page;
+        }
+        if let Some(timezone) = self.timezone {
+            build_options.timezone = timezone;
+        }
+        if let Some(time_format) = &self.time_format {
+            build_options.time_format = time_format.clone();
+        }
+        build_options.incremental = self.incremental;
+        build_options.history_limit = HistoryLimit {
+            max_messages: self.max_messages,
+            oldest_message_time: self.since,
+        };
+        build_options.channel_filter = self.filters.clone();
+
+        build_options
+    }
+}
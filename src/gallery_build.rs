@@ -0,0 +1,647 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::{SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use handlebars::Handlebars;
+use once_cell::sync::Lazy;
+use serde_derive::{Deserialize, Serialize};
+use twilight_model::id::Id;
+use twilight_model::id::marker::{ChannelMarker, MessageMarker};
+
+use crate::channel_history::HistoryLimit;
+use crate::image_metadata::CameraInfo;
+use crate::near_duplicates::collapse_near_duplicates;
+use crate::prune::{prune_unreferenced_files, PruneMode, referenced_files};
+use crate::source::{BasicAttachmentInfo, BasicChannelInfo, BasicMessageInfo, BasicChannelKind, BasicGuildInfo, MessageSource, SourceResult};
+use crate::sync_state::SyncState;
+use crate::thumbnail_download::{DEFAULT_THUMBNAIL_WIDTHS, ThumbnailDownloader, ThumbnailFormat, ThumbnailManifest};
+use crate::website::builder::gallery_page_info::{Gallery, GalleryPageInfo, GalleryPictureInfo};
+use crate::website::builder::{photo_page_url, render_site, slugify};
+use crate::website::dates::{BuildTime, DEFAULT_TIME_FORMAT};
+use crate::website::publish::StagedWebsite;
+use crate::website::theme::Theme;
+use crate::website::write_whole_website_directory;
+
+pub const DEFAULT_PAGE_TITLE_TEMPLATE: &str = "{{guild_name}} Photo Galleries";
+pub const DEFAULT_GALLERY_TITLE_TEMPLATE: &str = "{{channel_author_name}} ({{discord_author_name}})";
+pub const DEFAULT_PICTURES_PER_PAGE: usize = 100;
+const POSTED_DATE_FORMAT: &str = "%B %-d, %Y";
+
+/// Titles end up escaped by the page template, so they must not be escaped here too
+static TITLE_HANDLEBARS: Lazy<Handlebars> = Lazy::new(|| {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(handlebars::no_escape);
+    handlebars
+});
+
+#[derive(Serialize)]
+struct PageTitleData<'a> {
+    guild_name: &'a str,
+    category_name: &'a str,
+}
+
+#[derive(Serialize)]
+struct GalleryTitleData<'a> {
+    channel_name: &'a str,
+    channel_author_name: &'a str,
+    discord_author_name: &'a str,
+    picture_count: usize,
+}
+
+/// Everything that decides what a built gallery website looks like, apart from which guild category it is built from
+#[derive(Debug, Clone)]
+pub struct GalleryBuildOptions {
+    pub website_root: PathBuf,
+    /// URL the website root is published at, given out by the `/gallery link` command
+    pub public_url: Option<String>,
+    /// Handlebars template for the page title, can use `guild_name` and `category_name`
+    pub page_title_template: String,
+    /// Handlebars template for each gallery title, can use `channel_name`, `channel_author_name`,
+    /// `discord_author_name` and `picture_count`
+    pub gallery_title_template: String,
+    pub channel_parse_mode: ChannelParseMode,
+    /// Widths of the thumbnails made for every picture, browsers pick between them with `srcset`
+    pub thumbnail_widths: Vec<u32>,
+    pub thumbnail_format: ThumbnailFormat,
+    /// Download full size images into the website instead of linking to discord, whose links expire
+    pub mirror_originals: bool,
+    /// Images larger than this many bytes are linked to on discord even when mirroring originals
+    pub max_original_size: Option<u64>,
+    /// Show the camera and exposure details in the preview, and keep them in originals when those are mirrored. GPS and
+    /// other identifying metadata is always removed.
+    pub keep_camera_info: bool,
+    /// Show only the largest of each group of near-duplicate pictures in a gallery, such as edits and crops of one shot
+    pub collapse_near_duplicates: bool,
+    /// What to do with downloaded images the website no longer uses once it's written
+    pub prune_mode: PruneMode,
+    /// Publish the website even when downloads failed in a way a later build may fix, such as timeouts, showing
+    /// placeholders and linking to discord until then
+    pub allow_failed_downloads: bool,
+    /// Folder of templates, partials and resources used in place of the built-in ones
+    pub theme_folder: Option<PathBuf>,
+    /// Most pictures shown on one page of a gallery, the rest are split across further pages
+    pub pictures_per_page: usize,
+    /// Timezone the build time and the dates of pictures are shown in
+    pub timezone: Tz,
+    /// strftime style format of the build time, and of dates given to the `format_date` helper without one
+    pub time_format: String,
+    pub history_limit: HistoryLimit,
+    pub channel_filter: ChannelFilter,
+    /// Keep a sync state file in the website root, so only messages newer than the last build are fetched
+    pub incremental: bool,
+    /// Refetch every channel's whole history even when building incrementally, to pick up edits and deletions
+    pub full_resync: bool,
+}
+
+impl GalleryBuildOptions {
+    pub fn new(website_root: PathBuf) -> GalleryBuildOptions {
+        GalleryBuildOptions {
+            website_root,
+            public_url: None,
+            page_title_template: DEFAULT_PAGE_TITLE_TEMPLATE.to_owned(),
+            gallery_title_template: DEFAULT_GALLERY_TITLE_TEMPLATE.to_owned(),
+            channel_parse_mode: ChannelParseMode::FirstFullLastInitial,
+            thumbnail_widths: DEFAULT_THUMBNAIL_WIDTHS.to_vec(),
+            thumbnail_format: ThumbnailFormat::Jpeg,
+            mirror_originals: false,
+            max_original_size: None,
+            keep_camera_info: false,
+            collapse_near_duplicates: false,
+            prune_mode: PruneMode::Keep,
+            allow_failed_downloads: false,
+            theme_folder: None,
+            pictures_per_page: DEFAULT_PICTURES_PER_PAGE,
+            timezone: Tz::UTC,
+            time_format: DEFAULT_TIME_FORMAT.to_owned(),
+            history_limit: HistoryLimit::default(),
+            channel_filter: ChannelFilter::default(),
+            incremental: false,
+            full_resync: false,
+        }
+    }
+
+    /// Renders the page and gallery title templates with made up data, so mistakes in them are found before building
+    pub fn check_title_templates(&self) -> Result<(), String> {
+        TITLE_HANDLEBARS.render_template(&self.page_title_template, &PageTitleData {
+            guild_name: "guild",
+            category_name: "category",
+        }).map_err(|err| format!("Invalid page title template `{}`: {}", self.page_title_template, err))?;
+        TITLE_HANDLEBARS.render_template(&self.gallery_title_template, &GalleryTitleData {
+            channel_name: "first-last",
+            channel_author_name: "First L.",
+            discord_author_name: "first",
+            picture_count: 1,
+        }).map_err(|err| format!("Invalid gallery title template `{}`: {}", self.gallery_title_template, err))?;
+
+        Ok(())
+    }
+
+    /// Checks there's at least one thumbnail width and that none of them are zero
+    pub fn check_thumbnail_widths(&self) -> Result<(), String> {
+        if self.thumbnail_widths.is_empty() {
+            return Err("At least one thumbnail width is needed".to_owned());
+        }
+        if self.thumbnail_widths.contains(&0) {
+            return Err("Thumbnail widths must be more than 0".to_owned());
+        }
+
+        Ok(())
+    }
+
+    fn should_mirror_original(&self, attachment: &BasicAttachmentInfo) -> bool {
+        if !self.mirror_originals {
+            return false;
+        }
+
+        match (self.max_original_size, attachment.size) {
+            (Some(max_original_size), Some(size)) => size <= max_original_size,
+            _ => true,
+        }
+    }
+}
+
+/// Decides which channels of a category get turned into galleries
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelFilter {
+    /// If not empty, only channels whose name or ID is in this list are used
+    pub include_channels: Vec<String>,
+    /// Channels whose name or ID is in this list are never used
+    pub exclude_channels: Vec<String>,
+    /// Galleries with fewer pictures than this are left out
+    pub min_pictures: usize,
+}
+
+impl ChannelFilter {
+    fn allows_channel(&self, channel: &BasicChannelInfo) -> bool {
+        let channel_matches = |choice: &String| crate::matches_name_or_id(choice, channel.id.get(), channel.name.as_deref());
+
+        (self.include_channels.is_empty() || self.include_channels.iter().any(channel_matches))
+            && !self.exclude_channels.iter().any(channel_matches)
+    }
+}
+
+/// A guild category to build a gallery website from, and how to build it
+#[derive(Debug, Clone)]
+pub struct GalleryBuildTarget {
+    pub guild: BasicGuildInfo,
+    pub category: BasicChannelInfo,
+    pub guild_channels: Vec<BasicChannelInfo>,
+    pub build_options: GalleryBuildOptions,
+}
+
+impl GalleryBuildTarget {
+    /// Returns the channels of the category that become galleries
+    pub fn gallery_channels(&self) -> Vec<&BasicChannelInfo> {
+        gallery_channels(&self.category, &self.guild_channels, &self.build_options)
+    }
+}
+
+/// Returns the channels of a category that become galleries
+fn gallery_channels<'a>(chosen_category: &BasicChannelInfo, guild_channels: &'a [BasicChannelInfo], build_options: &GalleryBuildOptions) -> Vec<&'a BasicChannelInfo> {
+    guild_channels.iter()
+        .filter(|c| c.parent_id == Some(chosen_category.id) && c.kind == BasicChannelKind::Text)
+        .filter(|c| build_options.channel_filter.allows_channel(c))
+        .collect()
+}
+
+/// Fetches the messages of every gallery channel, then writes the whole website
+pub async fn build_gallery_website(source: &dyn MessageSource, build_target: &GalleryBuildTarget) -> SourceResult<()> {
+    let mut sync_state = sync_gallery_channels(source, build_target).await?;
+
+    write_gallery_website(source, &mut sync_state, build_target).await
+}
+
+/// Brings the messages of every gallery channel up to date, starting from the website's sync state when building
+/// incrementally
+pub async fn sync_gallery_channels(source: &dyn MessageSource, build_target: &GalleryBuildTarget) -> SourceResult<SyncState> {
+    let build_options = &build_target.build_options;
+    let category_channels = build_target.gallery_channels();
+
+    let mut sync_state = if build_options.incremental {
+        SyncState::load(&build_options.website_root)?
+    } else {
+        SyncState::default()
+    };
+    sync_state.retain_channels(&category_channels.iter().map(|channel| channel.id).collect::<Vec<_>>());
+
+    for channel in category_channels {
+        sync_state.sync_channel(source, channel.id, &build_options.history_limit, build_options.full_resync).await?;
+    }
+
+    Ok(sync_state)
+}
+
+/// Writes the website from already synced messages into a staging copy, downloading any thumbnails it doesn't have yet,
+/// then swaps it in for the live website. Messages whose attachment urls have expired are refetched from `source`.
+pub async fn write_gallery_website(source: &dyn MessageSource, sync_state: &mut SyncState, build_target: &GalleryBuildTarget) -> SourceResult<()> {
+    let GalleryBuildTarget { guild: chosen_guild, category: chosen_category, build_options, .. } = build_target;
+    build_options.thumbnail_format.check_available()?;
+    let build_time = BuildTime::now(build_options.timezone, &build_options.time_format)?;
+    let mut theme = Theme::load(build_options.theme_folder.as_deref())?;
+    build_time.register_helpers(&mut theme.handlebars);
+    let staged_website = StagedWebsite::prepare(&build_options.website_root)?;
+    let staging_root = staged_website.path().to_path_buf();
+    let website_root = staging_root.as_path();
+    let category_channels = build_target.gallery_channels();
+    // let category_names = category_channels.map(|c| c.name.as_ref().unwrap()).collect::<Vec<_>>();
+    // println!("Guild category `{}` with channels: {:?}", chosen_category.name.as_ref().unwrap(), category_names);
+
+    let mut galleries = Vec::new();
+    let mut gallery_channel_ids = Vec::new();
+
+    let thumbnail_manifest = ThumbnailManifest::load(website_root)?;
+    let thumbnail_downloader = ThumbnailDownloader::new(build_options.thumbnail_widths.clone(), build_options.thumbnail_format, build_options.keep_camera_info, thumbnail_manifest);
+
+    // Messages cached by earlier incremental builds hold urls that have expired since, which only matters for pictures
+    // that still have to be downloaded
+    let channel_ids = category_channels.iter().map(|channel| channel.id).collect::<Vec<_>>();
+    let stale_messages = messages_with_attachments(sync_state, &channel_ids, |attachment| {
+        attachment.urls_expired() && needs_download(&thumbnail_downloader, website_root, build_options, attachment)
+    });
+    refetch_messages(source, sync_state, stale_messages).await;
+
+    let thumbnail_downloader = Arc::new(std::sync::Mutex::new(thumbnail_downloader));
+
+    for channel in category_channels {
+        let channel_messages = sync_state.picture_messages(channel.id).to_vec();
+
+        if channel_messages.is_empty() {
+            continue;
+        }
+
+        let author_discord_name = {
+            let mut counts = BTreeMap::new();
+            for message in channel_messages.iter() {
+                if message.attachments.iter().any(BasicAttachmentInfo::is_image) {
+                    *counts.entry(&message.author_name).or_insert(0) += 1;
+                }
+            }
+
+            if counts.is_empty() {
+                continue;
+            }
+
+            let max = counts.into_iter().max_by_key(|&(_, count)| count).unwrap();
+            max.0.clone()
+        };
+
+        let picture_count = channel_messages.iter().flat_map(|message| &message.attachments).filter(|attachment| attachment.is_image()).count();
+        if picture_count < build_options.channel_filter.min_pictures {
+            continue;
+        }
+
+        let gallery_picture_infos = channel_messages
+            .into_iter()
+            .rev()
+            .flat_map(|message| {
+                let picture_description = if message.content.is_empty() {
+                    None
+                } else {
+                    Some(message.content.clone())
+                };
+                let posted_at = Utc.timestamp_micros(message.timestamp.as_micros()).single().unwrap_or_default();
+                let posted_date = build_time.format(posted_at, POSTED_DATE_FORMAT);
+                let message_id = message.id.to_string();
+                let message_url = format!("https://discord.com/channels/{}/{}/{}", chosen_guild.id, channel.id, message.id);
+                let author_name = message.author_name;
+                let thumbnail_downloader = thumbnail_downloader.clone();
+                message
+                    .attachments
+                    .into_iter()
+                    .filter(BasicAttachmentInfo::is_image)
+                    .map(move |attachment| {
+                        let picture_description = picture_description.clone();
+                        let author_name = author_name.clone();
+                        let posted_date = posted_date.clone();
+                        let message_id = message_id.clone();
+                        let message_url = message_url.clone();
+                        let mut thumbnail_downloader = thumbnail_downloader.lock().unwrap();
+                        let attachment_id = attachment.id.to_string();
+                        thumbnail_downloader.queue_download(website_root, &attachment_id, &attachment.proxy_url);
+                        if build_options.should_mirror_original(&attachment) {
+                            thumbnail_downloader.queue_original_download(website_root, &attachment_id, &attachment.url);
+                        }
+                        GalleryPictureInfo {
+                            picture_description,
+                            // Switched to the mirrored original once it's saved
+                            full_url: attachment.proxy_url,
+                            photo_url: String::new(),
+                            thumbnail_url: String::new(),
+                            thumbnail_variants: Vec::new(),
+                            thumbnail_sources: Vec::new(),
+                            thumbnail_width: None,
+                            thumbnail_height: None,
+                            placeholder_color: None,
+                            variant_count: 0,
+                            collapsed_permalink_urls: Vec::new(),
+                            camera_info: CameraInfo::default(),
+                            author_name,
+                            posted_at: posted_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                            posted_date,
+                            message_id,
+                            message_url,
+                            permalink_url: photo_page_url(&attachment_id),
+                            attachment_id,
+                            filename: attachment.filename,
+                            width: attachment.width,
+                            height: attachment.height,
+                            size: attachment.size,
+                        }
+                    })
+            }).collect::<Vec<_>>();
+
+        let channel_name = channel.name.as_deref().unwrap_or("No channel name?");
+        let author_name_channel = parse_author_name_from_channel_name(channel_name, build_options.channel_parse_mode);
+
+        let gallery_title = TITLE_HANDLEBARS.render_template(&build_options.gallery_title_template, &GalleryTitleData {
+            channel_name,
+            channel_author_name: &author_name_channel,
+            discord_author_name: &author_discord_name,
+            picture_count,
+        })?;
+
+        galleries.push(
+            Gallery {
+                gallery_title,
+                slug: slugify(channel_name),
+                gallery_picture_infos,
+            }
+        );
+        gallery_channel_ids.push(channel.id);
+    }
+
+    // Channels whose names make the same slug, or none at all, are told apart by their ids instead
+    let mut slug_counts = HashMap::new();
+    for gallery in &galleries {
+        *slug_counts.entry(gallery.slug.clone()).or_insert(0) += 1;
+    }
+    for (gallery, channel_id) in galleries.iter_mut().zip(gallery_channel_ids) {
+        if gallery.slug.is_empty() {
+            gallery.slug = channel_id.to_string();
+        } else if slug_counts[&gallery.slug] > 1 {
+            gallery.slug = format!("{}-{}", gallery.slug, channel_id);
+        }
+    }
+
+    galleries.sort_unstable_by(|g1, g2| g1.gallery_title.cmp(&g2.gallery_title));
+
+    // Thumbnails are made before the page is written, so it never points at ones that don't exist yet
+    let mut thumbnail_downloader = Arc::try_unwrap(thumbnail_downloader).unwrap_or_else(|_| panic!("")).into_inner().unwrap();
+    let mut failed_downloads = thumbnail_downloader.download_all(website_root).await;
+
+    // Discord refuses expired links, so those attachments are downloaded once more with fresh urls from their messages
+    let refused_attachment_ids = failed_downloads.iter()
+        .filter(|failed_download| failed_download.error.is_refused_link())
+        .map(|failed_download| failed_download.attachment_id.clone())
+        .collect::<HashSet<_>>();
+    let mut refreshed_attachments = HashMap::new();
+    if !refused_attachment_ids.is_empty() {
+        let refused_messages = messages_with_attachments(sync_state, &channel_ids, |attachment| refused_attachment_ids.contains(&attachment.id.to_string()));
+        let refetched_messages = refetch_messages(source, sync_state, refused_messages).await;
+        thumbnail_downloader.set_fresh_urls();
+
+        for attachment in refetched_messages.iter().flat_map(|message| &message.attachments) {
+            let attachment_id = attachment.id.to_string();
+            if refused_attachment_ids.contains(&attachment_id) {
+                thumbnail_downloader.queue_download(website_root, &attachment_id, &attachment.proxy_url);
+                if build_options.should_mirror_original(attachment) {
+                    thumbnail_downloader.queue_original_download(website_root, &attachment_id, &attachment.url);
+                }
+                refreshed_attachments.insert(attachment_id, attachment.proxy_url.clone());
+            }
+        }
+        if !refreshed_attachments.is_empty() {
+            failed_downloads.retain(|failed_download| !refreshed_attachments.contains_key(&failed_download.attachment_id));
+            failed_downloads.extend(thumbnail_downloader.download_all(website_root).await);
+        }
+    }
+    thumbnail_downloader.manifest().save(website_root)?;
+
+    // Published websites stay as they are until the next build, so one missing downloads that only failed this time
+    // isn't published over the last one. The downloads that worked are kept for the next build.
+    let retried_download_count = failed_downloads.iter().filter(|failed_download| failed_download.error.is_transient()).count();
+    if retried_download_count > 0 && !build_options.allow_failed_downloads {
+        staged_website.hold_back()?;
+        return Err(format!(
+            "{} downloads failed in a way another build may fix, so `{}` wasn't published. Build again to carry on from the downloads that worked, or use `--allow-failed-downloads` to publish it anyway",
+            retried_download_count, build_options.website_root.display(),
+        ).into());
+    }
+
+    for gallery_picture_info in galleries.iter_mut().flat_map(|gallery| &mut gallery.gallery_picture_infos) {
+        let mut thumbnail_sources = thumbnail_downloader.thumbnail_sets(&gallery_picture_info.attachment_id);
+        let jpeg_thumbnails = thumbnail_sources.pop().unwrap();
+        gallery_picture_info.thumbnail_url = jpeg_thumbnails.variants[0].url.clone();
+        gallery_picture_info.photo_url = jpeg_thumbnails.variants[jpeg_thumbnails.variants.len() - 1].url.clone();
+        gallery_picture_info.thumbnail_variants = jpeg_thumbnails.variants;
+        gallery_picture_info.thumbnail_sources = thumbnail_sources;
+        if let Some(proxy_url) = refreshed_attachments.get(&gallery_picture_info.attachment_id) {
+            gallery_picture_info.full_url = proxy_url.clone();
+        }
+        if let Some(original_url) = thumbnail_downloader.original_url(&gallery_picture_info.attachment_id) {
+            gallery_picture_info.full_url = original_url.to_owned();
+            gallery_picture_info.photo_url = original_url.to_owned();
+        }
+
+        let Some(image_info) = thumbnail_downloader.manifest().image_info(&gallery_picture_info.attachment_id) else {
+            continue;
+        };
+
+        gallery_picture_info.thumbnail_width = Some(image_info.thumbnail_width);
+        gallery_picture_info.thumbnail_height = Some(image_info.thumbnail_height);
+        gallery_picture_info.placeholder_color = Some(image_info.placeholder_color.clone());
+        let size_unknown = gallery_picture_info.width.is_none() || gallery_picture_info.height.is_none();
+        if size_unknown && !thumbnail_downloader.manifest().is_placeholder(&gallery_picture_info.attachment_id) {
+            gallery_picture_info.width = Some(image_info.source_width);
+            gallery_picture_info.height = Some(image_info.source_height);
+        }
+        if build_options.keep_camera_info {
+            gallery_picture_info.camera_info = image_info.camera_info.clone().unwrap_or_default();
+        }
+    }
+
+    // Collapsed near-duplicates aren't on the page, but their thumbnails are kept so they aren't remade by every build
+    let referenced_files = referenced_files(&galleries);
+
+    if build_options.collapse_near_duplicates {
+        let thumbnail_manifest = thumbnail_downloader.manifest();
+        for gallery in &mut galleries {
+            let gallery_picture_infos = mem::take(&mut gallery.gallery_picture_infos)
+                .into_iter()
+                .map(|gallery_picture_info| {
+                    let image_info = thumbnail_manifest.image_info(&gallery_picture_info.attachment_id);
+                    (gallery_picture_info, image_info)
+                })
+                .collect();
+            gallery.gallery_picture_infos = collapse_near_duplicates(gallery_picture_infos);
+        }
+    }
+
+    let page_title = TITLE_HANDLEBARS.render_template(&build_options.page_title_template, &PageTitleData {
+        guild_name: &chosen_guild.name,
+        category_name: chosen_category.name.as_deref().unwrap_or("No Category Name"),
+    })?;
+
+    let gallery_page_info = GalleryPageInfo {
+        page_title,
+        galleries,
+        guild_built_from: chosen_guild.name.clone(),
+        page_built_time: build_time.formatted(),
+    };
+
+    let rendered_pages = render_site(&theme, &gallery_page_info, build_options.pictures_per_page, build_options.public_url.as_deref())?;
+    write_whole_website_directory(website_root, &rendered_pages, &theme)?;
+    prune_unreferenced_files(website_root, &referenced_files, build_options.prune_mode)?;
+
+    if build_options.incremental {
+        sync_state.save(website_root)?;
+    }
+
+    staged_website.publish()?;
+
+    Ok(())
+}
+
+/// Whether an attachment's thumbnails or mirrored original still have to be downloaded
+fn needs_download(thumbnail_downloader: &ThumbnailDownloader, website_root: &Path, build_options: &GalleryBuildOptions, attachment: &BasicAttachmentInfo) -> bool {
+    attachment.is_image() && (!thumbnail_downloader.thumbnails_saved(website_root, &attachment.id.to_string())
+        || build_options.should_mirror_original(attachment) && !thumbnail_downloader.original_saved(website_root, &attachment.url))
+}
+
+/// Returns the synced messages with an attachment matching `predicate`
+fn messages_with_attachments(
+    sync_state: &SyncState,
+    channel_ids: &[Id<ChannelMarker>],
+    predicate: impl Fn(&BasicAttachmentInfo) -> bool,
+) -> Vec<(Id<ChannelMarker>, Id<MessageMarker>)> {
+    channel_ids.iter()
+        .flat_map(|&channel_id| sync_state.picture_messages(channel_id).iter().map(move |message| (channel_id, message)))
+        .filter(|(_, message)| message.attachments.iter().any(&predicate))
+        .map(|(channel_id, message)| (channel_id, message.id))
+        .collect()
+}
+
+/// Fetches messages again for fresh attachment urls, replacing them in the sync state, and returns the ones that were
+/// refetched. Messages that can't be fetched are left as they are, their pictures are retried by the next build.
+async fn refetch_messages(source: &dyn MessageSource, sync_state: &mut SyncState, messages: Vec<(Id<ChannelMarker>, Id<MessageMarker>)>) -> Vec<BasicMessageInfo> {
+    let mut refetched_messages = Vec::new();
+    if messages.is_empty() {
+        return refetched_messages;
+    }
+
+    println!("Refetching {} messages for fresh attachment urls", messages.len());
+    for (channel_id, message_id) in messages {
+        match source.refetch_message(channel_id, message_id).await {
+            Ok(Some(message)) => {
+                sync_state.upsert_message(channel_id, message.clone());
+                refetched_messages.push(message);
+            }
+            Ok(None) => {}
+            Err(err) => eprintln!("Error refetching message `{message_id}` in channel `{channel_id}`: {err}"),
+        }
+    }
+
+    refetched_messages
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChannelParseMode {
+    FullName,
+    FirstFullLastInitial,
+}
+
+fn parse_author_name_from_channel_name(channel_name: &str, channel_parse_mode: ChannelParseMode) -> String {
+    match channel_parse_mode {
+        ChannelParseMode::FullName => {
+            channel_name
+                .split('-')
+                .map(|s| {
+                    let mut chars = s.chars();
+                    let mut string = String::from(chars.next().unwrap().to_ascii_uppercase());
+                    string += &*chars.as_str().to_ascii_lowercase();
+
+                    string
+                })
+                .collect::<Vec<String>>()
+                .join(" ")
+        }
+        ChannelParseMode::FirstFullLastInitial => {
+            let channel_name_parts = channel_name.split('-').collect::<Vec<&str>>();
+            if channel_name_parts.len() >= 2 {
+                let mut first_name_chars = channel_name_parts[0].chars();
+                let first_initial = first_name_chars.next().unwrap().to_ascii_uppercase();
+                let rest_of_first_name = first_name_chars.as_str().to_ascii_lowercase();
+                let last_initial = channel_name_parts[1].chars().next().unwrap().to_ascii_uppercase();
+                format!("{}{} {}.", first_initial, rest_of_first_name, last_initial)
+            } else { // If there isn't 2 parts to the name just return the channel name, this means someone didn't name their channel right (Shame!)
+                channel_name.to_owned()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::source::fixture::FixtureSource;
+
+    #[test]
+    fn parses_author_names_from_channel_names() {
+        assert_eq!(parse_author_name_from_channel_name("jane-doe", ChannelParseMode::FirstFullLastInitial), "Jane D.");
+        assert_eq!(parse_author_name_from_channel_name("jane-doe", ChannelParseMode::FullName), "Jane Doe");
+        assert_eq!(parse_author_name_from_channel_name("jane", ChannelParseMode::FirstFullLastInitial), "jane");
+    }
+
+    #[tokio::test]
+    async fn builds_demo_website() {
+        let source = FixtureSource::open(Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/demo")).unwrap();
+        let guild = source.guilds().await.unwrap().remove(0);
+        let guild_channels = source.guild_channels(guild.id).await.unwrap();
+        let category = guild_channels.iter().find(|channel| channel.kind == BasicChannelKind::Category).unwrap().clone();
+
+        let website_root = std::env::temp_dir().join(format!("discord_photo_gallery_test_{}", std::process::id()));
+        let mut build_options = GalleryBuildOptions::new(website_root.clone());
+        build_options.pictures_per_page = 1;
+        let build_target = GalleryBuildTarget {
+            guild,
+            category,
+            guild_channels,
+            build_options,
+        };
+        let build_result = build_gallery_website(&source, &build_target).await;
+        let read_page = |path: &str| fs::read_to_string(website_root.join(path)).unwrap_or_else(|err| panic!("Failed to read `{path}`: {err}"));
+
+        let pages = build_result.map(|()| [
+            read_page("index.html"),
+            read_page("galleries/jane-doe/index.html"),
+            read_page("galleries/jane-doe/page-2.html"),
+            read_page("galleries/john-smith/index.html"),
+            read_page("photos/400000000000000001.html"),
+        ]);
+        fs::remove_dir_all(&website_root).unwrap();
+        let [index_page, first_gallery_page, second_gallery_page, other_gallery_page, photo_page] = pages.unwrap();
+
+        assert!(index_page.contains("galleries/jane-doe/index.html"));
+        assert!(index_page.contains("galleries/john-smith/index.html"));
+        assert!(!index_page.contains("general"));
+
+        // Oldest first, a picture per page
+        assert!(first_gallery_page.contains("photos/400000000000000001.html"));
+        assert!(first_gallery_page.contains("galleries/jane-doe/page-2.html"));
+        assert!(second_gallery_page.contains("photos/400000000000000002.html"));
+        assert!(other_gallery_page.contains("John S."));
+
+        assert!(photo_page.contains("Sunset from the pier"));
+        assert!(photo_page.contains("galleries/jane-doe/index.html"));
+        assert!(photo_page.contains("photos/400000000000000002.html"));
+        // Originals aren't mirrored, so the page shows the largest thumbnail rather than linking the image on discord
+        assert!(photo_page.contains(r#"<img src="thumbnails/"#));
+    }
+}
@@ -0,0 +1,428 @@
+//! Reading and stripping the EXIF metadata phones and cameras put in images.
+//!
+//! Thumbnails are re-encoded without any metadata, so only mirrored originals have to be stripped. Their pixels are
+//! copied untouched, only the metadata segments around them are rewritten.
+
+use std::error::Error;
+use std::io::Cursor;
+
+use exif::{Exif, Field, In, Tag, Value};
+use image::DynamicImage;
+use serde_derive::{Deserialize, Serialize};
+
+/// Fields kept in originals when camera info is kept. None of them say where a photo was taken or who owns the camera.
+const CAMERA_INFO_TAGS: [Tag; 10] = [
+    Tag::Make,
+    Tag::Model,
+    Tag::LensMake,
+    Tag::LensModel,
+    Tag::ExposureTime,
+    Tag::FNumber,
+    Tag::PhotographicSensitivity,
+    Tag::FocalLength,
+    Tag::DateTimeOriginal,
+    Tag::OffsetTimeOriginal,
+];
+
+const JPEG_SOI: u8 = 0xD8;
+const JPEG_EOI: u8 = 0xD9;
+const JPEG_SOS: u8 = 0xDA;
+/// Restart markers, which appear within image data without a length
+const JPEG_RST: std::ops::RangeInclusive<u8> = 0xD0..=0xD7;
+const JPEG_APP1: u8 = 0xE1;
+/// Holds colour profiles, but also the index of MPF secondary images, such as depth maps and previews
+const JPEG_APP2: u8 = 0xE2;
+const ICC_PROFILE_APP2_HEADER: &[u8] = b"ICC_PROFILE\0";
+/// Photoshop IRB, which holds IPTC data such as the author and location
+const JPEG_APP13: u8 = 0xED;
+const JPEG_COM: u8 = 0xFE;
+const EXIF_APP1_HEADER: &[u8] = b"Exif\0\0";
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// Chunks that can hold EXIF, free form text (often the author or software) and when the image was last edited
+const PNG_METADATA_CHUNKS: [&[u8; 4]; 5] = [b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+const WEBP_METADATA_CHUNKS: [&[u8; 4]; 2] = [b"EXIF", b"XMP "];
+/// Flags in the VP8X chunk saying the EXIF and XMP chunks are present
+const WEBP_VP8X_METADATA_FLAGS: u8 = 0b0000_1100;
+
+/// Details of how a photo was taken, formatted for showing to people
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CameraInfo {
+    pub camera: Option<String>,
+    pub lens: Option<String>,
+    /// Exposure time, aperture, ISO and focal length, such as `1/250 s f/2.8 ISO 100 50 mm`
+    pub exposure: Option<String>,
+    /// When the photo was taken in the camera's local time, as `YYYY-MM-DD HH:MM:SS`
+    pub taken_at: Option<String>,
+}
+
+pub fn read_exif(image_bytes: &[u8]) -> Option<Exif> {
+    exif::Reader::new().read_from_container(&mut Cursor::new(image_bytes)).ok()
+}
+
+/// Rotates and flips a decoded image so it's the right way up, as `image` ignores the EXIF orientation
+pub fn apply_orientation(image: DynamicImage, exif: Option<&Exif>) -> DynamicImage {
+    let orientation = exif
+        .and_then(|exif| exif.get_field(Tag::Orientation, In::PRIMARY))
+        .and_then(|field| field.value.get_uint(0))
+        .unwrap_or(1);
+
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Reads the camera details of a photo, or nothing if it has none of them
+pub fn read_camera_info(exif: &Exif) -> Option<CameraInfo> {
+    let make = ascii_field(exif, Tag::Make);
+    let model = ascii_field(exif, Tag::Model);
+    // Most models already start with the make, like `Canon EOS R6`
+    let camera = match (make, model) {
+        (Some(make), Some(model)) if !model.to_lowercase().starts_with(&make.to_lowercase()) => Some(format!("{make} {model}")),
+        (make, model) => model.or(make),
+    };
+
+    let lens = ascii_field(exif, Tag::LensModel).or_else(|| ascii_field(exif, Tag::LensMake));
+
+    let exposure_parts = [
+        unit_field(exif, Tag::ExposureTime),
+        unit_field(exif, Tag::FNumber),
+        exif.get_field(Tag::PhotographicSensitivity, In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+            .map(|iso| format!("ISO {iso}")),
+        unit_field(exif, Tag::FocalLength),
+    ];
+    let exposure = exposure_parts.into_iter().flatten().collect::<Vec<_>>().join(" ");
+    let exposure = Some(exposure).filter(|exposure| !exposure.is_empty());
+
+    let taken_at = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY)
+        .and_then(|field| match &field.value {
+            Value::Ascii(ascii) => ascii.first().and_then(|ascii| exif::DateTime::from_ascii(ascii).ok()),
+            _ => None,
+        })
+        .map(|date_time| format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            date_time.year, date_time.month, date_time.day, date_time.hour, date_time.minute, date_time.second,
+        ));
+
+    let camera_info = CameraInfo {
+        camera,
+        lens,
+        exposure,
+        taken_at,
+    };
+
+    (camera_info != CameraInfo::default()).then_some(camera_info)
+}
+
+fn ascii_field(exif: &Exif, tag: Tag) -> Option<String> {
+    let field = exif.get_field(tag, In::PRIMARY)?;
+    let Value::Ascii(ascii) = &field.value else {
+        return None;
+    };
+    let text = String::from_utf8_lossy(ascii.first()?).trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_owned();
+
+    Some(text).filter(|text| !text.is_empty())
+}
+
+/// Formats a field with its unit, such as `1/250 s` or `f/2.8`
+fn unit_field(exif: &Exif, tag: Tag) -> Option<String> {
+    let field = exif.get_field(tag, In::PRIMARY)?;
+
+    Some(field.display_value().with_unit(exif).to_string())
+}
+
+/// Removes GPS and other identifying metadata from a JPEG, PNG or WebP image. Other formats, such as TIFF, HEIC and
+/// AVIF, are refused, as they can't be mirrored without their metadata.
+///
+/// JPEGs keep their orientation, so browsers still show them the right way up, along with the camera and exposure
+/// details if `keep_camera_info` is set.
+pub fn strip_metadata(image_bytes: &[u8], keep_camera_info: bool) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    if image_bytes.starts_with(&[0xFF, JPEG_SOI]) {
+        strip_jpeg_metadata(image_bytes, keep_camera_info)
+    } else if image_bytes.starts_with(PNG_SIGNATURE) {
+        strip_png_metadata(image_bytes)
+    } else if image_bytes.starts_with(b"RIFF") && image_bytes.get(8..12) == Some(b"WEBP") {
+        strip_webp_metadata(image_bytes)
+    } else {
+        Err("only metadata of JPEG, PNG and WebP images can be removed, so other formats aren't mirrored".into())
+    }
+}
+
+fn strip_jpeg_metadata(image_bytes: &[u8], keep_camera_info: bool) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut stripped_bytes = image_bytes[..2].to_vec();
+    if let Some(exif_segment) = read_exif(image_bytes).and_then(|exif| kept_exif_segment(&exif, keep_camera_info)) {
+        stripped_bytes.extend_from_slice(&exif_segment);
+    }
+
+    let mut position = 2;
+    loop {
+        // Markers may be padded with any number of 0xFF bytes
+        while image_bytes.get(position + 1) == Some(&0xFF) {
+            position += 1;
+        }
+        let (Some(0xFF), Some(&marker)) = (image_bytes.get(position), image_bytes.get(position + 1)) else {
+            return Err("JPEG segment doesn't start with a marker".into());
+        };
+
+        // Anything after the end of the image, such as MPF secondary images, is left out
+        if marker == JPEG_EOI {
+            stripped_bytes.extend_from_slice(&image_bytes[position..position + 2]);
+            return Ok(stripped_bytes);
+        }
+
+        let segment_length = match image_bytes.get(position + 2..position + 4) {
+            Some(length_bytes) => u16::from_be_bytes([length_bytes[0], length_bytes[1]]) as usize,
+            None => return Err("JPEG ended before the image data".into()),
+        };
+        let segment_end = position + 2 + segment_length;
+        if segment_length < 2 || segment_end > image_bytes.len() {
+            return Err("JPEG segment is longer than the file".into());
+        }
+
+        // APP1 holds both EXIF and XMP, the EXIF worth keeping was already written above
+        let is_metadata = match marker {
+            JPEG_APP1 | JPEG_APP13 | JPEG_COM => true,
+            JPEG_APP2 => !image_bytes[position + 4..segment_end].starts_with(ICC_PROFILE_APP2_HEADER),
+            _ => false,
+        };
+        if !is_metadata {
+            stripped_bytes.extend_from_slice(&image_bytes[position..segment_end]);
+        }
+        position = segment_end;
+
+        // The image data of a scan follows its header, up to the next marker that isn't a stuffed byte or a restart
+        if marker == JPEG_SOS {
+            let scan_end = (position..image_bytes.len().saturating_sub(1))
+                .find(|&index| image_bytes[index] == 0xFF && image_bytes[index + 1] != 0 && !JPEG_RST.contains(&image_bytes[index + 1]));
+            // Cut off JPEGs still show the part that was saved
+            let Some(scan_end) = scan_end else {
+                stripped_bytes.extend_from_slice(&image_bytes[position..]);
+                return Ok(stripped_bytes);
+            };
+            stripped_bytes.extend_from_slice(&image_bytes[position..scan_end]);
+            position = scan_end;
+        }
+    }
+}
+
+/// Builds an APP1 segment holding only the orientation and, if kept, the camera info of the original EXIF
+fn kept_exif_segment(exif: &Exif, keep_camera_info: bool) -> Option<Vec<u8>> {
+    let kept_tags = if keep_camera_info {
+        &CAMERA_INFO_TAGS[..]
+    } else {
+        &[]
+    };
+    let kept_fields = [Tag::Orientation].iter().chain(kept_tags)
+        .filter_map(|tag| exif.get_field(*tag, In::PRIMARY))
+        .collect::<Vec<&Field>>();
+    if kept_fields.is_empty() {
+        return None;
+    }
+
+    let mut writer = exif::experimental::Writer::new();
+    for field in kept_fields {
+        writer.push_field(field);
+    }
+    let mut tiff_bytes = Cursor::new(Vec::new());
+    writer.write(&mut tiff_bytes, exif.little_endian()).ok()?;
+    let tiff_bytes = tiff_bytes.into_inner();
+
+    let segment_length = u16::try_from(2 + EXIF_APP1_HEADER.len() + tiff_bytes.len()).ok()?;
+    let mut segment = vec![0xFF, JPEG_APP1];
+    segment.extend_from_slice(&segment_length.to_be_bytes());
+    segment.extend_from_slice(EXIF_APP1_HEADER);
+    segment.extend_from_slice(&tiff_bytes);
+
+    Some(segment)
+}
+
+fn strip_png_metadata(image_bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut stripped_bytes = PNG_SIGNATURE.to_vec();
+
+    let mut position = PNG_SIGNATURE.len();
+    while position < image_bytes.len() {
+        // Length, type, data and CRC
+        let Some(length_bytes) = image_bytes.get(position..position + 4) else {
+            return Err("PNG chunk is cut off".into());
+        };
+        let chunk_length = u32::from_be_bytes(length_bytes.try_into().unwrap()) as usize;
+        let chunk_end = position + 12 + chunk_length;
+        if chunk_end > image_bytes.len() {
+            return Err("PNG chunk is longer than the file".into());
+        }
+
+        let chunk_type = &image_bytes[position + 4..position + 8];
+        if !PNG_METADATA_CHUNKS.iter().any(|metadata_chunk| chunk_type == *metadata_chunk) {
+            stripped_bytes.extend_from_slice(&image_bytes[position..chunk_end]);
+        }
+        position = chunk_end;
+    }
+
+    Ok(stripped_bytes)
+}
+
+fn strip_webp_metadata(image_bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    // The RIFF size is filled in once the chunks are known
+    let mut stripped_bytes = image_bytes[..12].to_vec();
+
+    let mut position = 12;
+    while position < image_bytes.len() {
+        // FourCC, size and data, padded to an even length
+        let Some(size_bytes) = image_bytes.get(position + 4..position + 8) else {
+            return Err("WebP chunk is cut off".into());
+        };
+        let chunk_size = u32::from_le_bytes(size_bytes.try_into().unwrap()) as usize;
+        let chunk_end = (position + 8 + chunk_size + chunk_size % 2).min(image_bytes.len());
+        if position + 8 + chunk_size > image_bytes.len() {
+            return Err("WebP chunk is longer than the file".into());
+        }
+
+        let chunk_type = &image_bytes[position..position + 4];
+        if !WEBP_METADATA_CHUNKS.iter().any(|metadata_chunk| chunk_type == *metadata_chunk) {
+            let chunk_start = stripped_bytes.len();
+            stripped_bytes.extend_from_slice(&image_bytes[position..chunk_end]);
+            // The flags are the first byte of the chunk, which is 10 bytes long in any valid file
+            if chunk_type == b"VP8X" && chunk_size >= 1 {
+                stripped_bytes[chunk_start + 8] &= !WEBP_VP8X_METADATA_FLAGS;
+            }
+        }
+        position = chunk_end;
+    }
+
+    let riff_size = u32::try_from(stripped_bytes.len() - 8)?;
+    stripped_bytes[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+    Ok(stripped_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jpeg_segment(marker: u8, data: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(data);
+        segment
+    }
+
+    fn png_chunk(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        // Chunk CRCs aren't checked when stripping
+        [&(data.len() as u32).to_be_bytes()[..], chunk_type, data, &[0; 4]].concat()
+    }
+
+    fn webp(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut image_bytes = b"RIFF\0\0\0\0WEBP".to_vec();
+        for (chunk_type, data) in chunks {
+            image_bytes.extend_from_slice(*chunk_type);
+            image_bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            image_bytes.extend_from_slice(data);
+            if data.len() % 2 == 1 {
+                image_bytes.push(0);
+            }
+        }
+        let riff_size = image_bytes.len() as u32 - 8;
+        image_bytes[4..8].copy_from_slice(&riff_size.to_le_bytes());
+        image_bytes
+    }
+
+    #[test]
+    fn strips_jpeg_metadata_and_everything_after_the_image() {
+        let icc_profile = jpeg_segment(JPEG_APP2, b"ICC_PROFILE\0\x01\x01profile");
+        let scan = [jpeg_segment(JPEG_SOS, &[1, 1, 0, 0, 63, 0]), vec![0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD0, 0x56]].concat();
+        let image_bytes = [
+            vec![0xFF, JPEG_SOI],
+            jpeg_segment(JPEG_APP2, b"MPF\0index"),
+            icc_profile.clone(),
+            jpeg_segment(JPEG_COM, b"comment"),
+            jpeg_segment(JPEG_APP13, b"Photoshop 3.0\0"),
+            scan.clone(),
+            vec![0xFF, JPEG_EOI],
+            b"secondary image".to_vec(),
+        ].concat();
+
+        let stripped_bytes = strip_metadata(&image_bytes, false).unwrap();
+        assert_eq!(stripped_bytes, [vec![0xFF, JPEG_SOI], icc_profile, scan, vec![0xFF, JPEG_EOI]].concat());
+    }
+
+    #[test]
+    fn refuses_malformed_jpegs() {
+        let malformed_jpegs = [
+            vec![0xFF, JPEG_SOI],
+            vec![0xFF, JPEG_SOI, 0x00, 0x00],
+            vec![0xFF, JPEG_SOI, 0xFF, JPEG_COM, 0x00],
+            vec![0xFF, JPEG_SOI, 0xFF, JPEG_COM, 0x00, 0x01],
+            vec![0xFF, JPEG_SOI, 0xFF, JPEG_COM, 0xFF, 0xFF, 0x00],
+        ];
+
+        for image_bytes in malformed_jpegs {
+            assert!(strip_metadata(&image_bytes, true).is_err(), "{image_bytes:02x?} wasn't refused");
+        }
+    }
+
+    #[test]
+    fn strips_png_metadata() {
+        let header = png_chunk(b"IHDR", &[0; 13]);
+        let end = png_chunk(b"IEND", &[]);
+        let image_bytes = [PNG_SIGNATURE, &header, &png_chunk(b"tEXt", b"Author\0someone"), &png_chunk(b"eXIf", b"MM\0*"), &end].concat();
+
+        assert_eq!(strip_metadata(&image_bytes, false).unwrap(), [PNG_SIGNATURE, &header, &end].concat());
+    }
+
+    #[test]
+    fn refuses_malformed_pngs() {
+        let header = png_chunk(b"IHDR", &[0; 13]);
+        let malformed_pngs = [
+            [PNG_SIGNATURE, &[0, 0]].concat(),
+            [PNG_SIGNATURE, &header[..header.len() - 1]].concat(),
+            [PNG_SIGNATURE, &u32::MAX.to_be_bytes(), b"IDAT"].concat(),
+        ];
+
+        for image_bytes in malformed_pngs {
+            assert!(strip_metadata(&image_bytes, false).is_err(), "{image_bytes:02x?} wasn't refused");
+        }
+    }
+
+    #[test]
+    fn strips_webp_metadata() {
+        let vp8x = [WEBP_VP8X_METADATA_FLAGS, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let image_bytes = webp(&[(b"VP8X", &vp8x), (b"VP8L", b"pixels"), (b"EXIF", b"MM\0*"), (b"XMP ", b"<xmp/>")]);
+
+        assert_eq!(strip_metadata(&image_bytes, false).unwrap(), webp(&[(b"VP8X", &[0; 10]), (b"VP8L", b"pixels")]));
+    }
+
+    #[test]
+    fn refuses_malformed_webps() {
+        let mut cut_off_chunk = webp(&[(b"VP8L", b"pixels")]);
+        cut_off_chunk.truncate(cut_off_chunk.len() - 1);
+        let malformed_webps = [
+            cut_off_chunk,
+            [&webp(&[])[..], b"VP8L\0"].concat(),
+        ];
+
+        for image_bytes in malformed_webps {
+            assert!(strip_metadata(&image_bytes, false).is_err(), "{image_bytes:02x?} wasn't refused");
+        }
+    }
+
+    #[test]
+    fn keeps_empty_webp_vp8x_chunk_in_bounds() {
+        let image_bytes = b"RIFF\x0c\0\0\0WEBPVP8X\0\0\0\0";
+
+        assert_eq!(strip_metadata(image_bytes, false).unwrap(), image_bytes);
+    }
+
+    #[test]
+    fn refuses_formats_without_metadata_stripping() {
+        assert!(strip_metadata(b"II*\0\x08\0\0\0", false).is_err());
+    }
+}
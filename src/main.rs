@@ -1,28 +1,92 @@
 use std::{env, error::Error, io, mem, sync::Arc};
-use std::collections::BTreeMap;
 use std::io::Write;
 use std::ops::{Deref, DerefMut};
 use std::str::FromStr;
-use futures::StreamExt;
+use std::time::Duration;
 
+use clap::Parser;
 use tokio::io::AsyncBufReadExt;
 use tokio::sync::Mutex;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use twilight_cache_inmemory::{InMemoryCache, ResourceType};
 use twilight_gateway::{Event, Intents, Shard, ShardId};
 use twilight_http::Client as HttpClient;
-use twilight_model::channel::{Attachment, Channel, ChannelType};
 use twilight_model::guild::Guild;
-use crate::thumbnail_download::ThumbnailDownloader;
-
-use crate::website::builder::gallery_page_info::{Gallery, GalleryPageInfo, GalleryPictureInfo};
-use crate::website::builder::render_page;
-use crate::website::write_whole_website_directory;
+use twilight_model::id::Id;
+use twilight_model::id::marker::ApplicationMarker;
+use crate::cli::Cli;
+use crate::commands::handle_interaction;
+use crate::config::GalleryConfig;
+use crate::gallery_build::{build_gallery_website, GalleryBuildTarget};
+use crate::source::{BasicChannelInfo, BasicChannelKind, BasicGuildInfo, gallery_categories, MessageSource};
+use crate::source::chat_exporter::ChatExporterSource;
+use crate::source::fixture::FixtureSource;
+use crate::source::twilight::TwilightSource;
+use crate::watch::{watch_galleries, WatchEvent};
+use crate::website::publish::{resolve_website_root, rollback_website};
+use crate::website::theme::export_built_in_theme;
 
 pub mod website;
 pub mod thumbnail_download;
+pub mod channel_history;
+pub mod cli;
+pub mod commands;
+pub mod config;
+pub mod gallery_build;
+pub mod image_metadata;
+pub mod near_duplicates;
+pub mod prune;
+pub mod source;
+pub mod sync_state;
+pub mod watch;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let cli = Cli::parse();
+    if let Some(theme_folder) = &cli.export_theme {
+        return export_built_in_theme(theme_folder);
+    }
+
+    // Load the config before connecting so mistakes in it are reported straight away
+    let config = cli.config.as_ref().map(GalleryConfig::load).transpose()?;
+
+    if cli.rollback {
+        let website_roots = match &config {
+            Some(config) => config.select_profiles(&cli.profiles)?.into_iter().map(|(_, profile)| profile.output.clone()).collect(),
+            None => vec![cli.output.clone()],
+        };
+        for website_root in website_roots {
+            rollback_website(&website_root)?;
+        }
+
+        return Ok(());
+    }
+
+    if cli.watch {
+        let (twilight_source, watch_events) = connect_twilight_source().await?;
+        let build_targets = choose_build_targets(&twilight_source, config.as_ref(), &cli).await?;
+
+        return watch_galleries(&twilight_source, watch_events, build_targets, Duration::from_secs(cli.debounce)).await;
+    }
+
+    let source: Box<dyn MessageSource> = match (&cli.fixtures, &cli.chat_export) {
+        (Some(fixture_directory), _) => Box::new(FixtureSource::open(fixture_directory)?),
+        (_, Some(export_path)) => Box::new(ChatExporterSource::open(export_path)?),
+        (None, None) => Box::new(connect_twilight_source().await?.0),
+    };
+
+    for build_target in choose_build_targets(source.as_ref(), config.as_ref(), &cli).await? {
+        build_gallery_website(source.as_ref(), &build_target).await?;
+    }
+
+    Ok(())
+}
+
+/// Connects to the gateway and waits until every guild the bot is in has been received.
+///
+/// The gateway stays connected afterwards, sending every message event and gallery command it receives to the returned
+/// receiver.
+async fn connect_twilight_source() -> Result<(TwilightSource, UnboundedReceiver<WatchEvent>), Box<dyn Error + Send + Sync>> {
     let token = env::var("DISCORD_TOKEN")?;
 
     // Specify intents requesting events about things like new and updated messages in a guild and direct messages.
@@ -46,18 +110,24 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
     let (tx, mut rx): (_, tokio::sync::mpsc::Receiver<()>) = tokio::sync::mpsc::channel(3);
     let tx = Arc::new(tx);
+    let (watch_sender, watch_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let watch_sender = Arc::new(watch_sender);
     // Startup the event loop to process each event in the event stream as they
-    // come in.
-    loop {
-        tokio::select! {
-            next_event = shard.next_event() => {
-                match next_event {
+    // come in. It keeps running in the background for as long as the program does.
+    {
+        let http = http.clone();
+        let state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                match shard.next_event().await {
                     Ok(event) => {
                         // Update the cache.
                         cache.update(&event);
 
-                        // Spawn a new task to handle the event
-                        tokio::spawn(handle_event(event, Arc::clone(&http), state.clone(), tx.clone()));
+                        // Handle events in order, so a message being deleted is never seen before it was created
+                        if let Err(source) = handle_event(event, Arc::clone(&http), state.clone(), tx.clone(), watch_sender.clone()).await {
+                            tracing::warn!(?source, "error handling event");
+                        }
                     }
                     Err(source) => {
                         tracing::warn!(?source, "error receiving event");
@@ -67,39 +137,35 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
                         }
                     }
                 }
-            },
-
-            _ = rx.recv() => {
-                break;
             }
-        }
+        });
     }
 
+    // Either every guild was received, or the event loop stopped and dropped the sender
+    rx.recv().await;
 
-    if let State::Done { guilds } = state.lock().await.deref() {
-        ask_user_for_guild_channel(guilds.clone(), http.clone()).await;
+    let state = state.lock().await;
+    if let State::Done { application_id, guilds } = state.deref() {
+        Ok((TwilightSource::new(http, *application_id, guilds.clone()), watch_receiver))
     } else {
-        unreachable!()
+        Err("Gateway connection closed before every guild was received".into())
     }
-
-    Ok(())
 }
 
-#[derive(Debug, Clone)]
-pub struct BasicGuildInfo {
-    name: String,
-    channels: Vec<Channel>,
-}
+/// A guild received from the gateway, along with all of its channels
+type GatewayGuild = (BasicGuildInfo, Vec<BasicChannelInfo>);
 
 #[derive(Debug)]
 pub enum State {
     PreReady {},
     Ready {
+        application_id: Id<ApplicationMarker>,
         total_guilds_to_load: usize,
-        guilds: Vec<BasicGuildInfo>,
+        guilds: Vec<GatewayGuild>,
     },
     Done {
-        guilds: Vec<BasicGuildInfo>,
+        application_id: Id<ApplicationMarker>,
+        guilds: Vec<GatewayGuild>,
     },
 }
 
@@ -108,6 +174,7 @@ async fn handle_event(
     http: Arc<HttpClient>,
     state: Arc<Mutex<State>>,
     done_sender: Arc<tokio::sync::mpsc::Sender<()>>,
+    watch_sender: Arc<UnboundedSender<WatchEvent>>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     match event {
         Event::Ready(ready) => {
@@ -116,6 +183,7 @@ async fn handle_event(
             let mut state = state.lock().await;
 
             *state = State::Ready {
+                application_id: ready.application.id,
                 total_guilds_to_load,
                 guilds: Vec::new(),
             };
@@ -124,41 +192,91 @@ async fn handle_event(
         Event::GuildCreate(g) => {
             let mut state = state.lock().await;
 
-            if let State::Ready { total_guilds_to_load, guilds } = state.deref_mut() {
-                let Guild { name, channels, .. } = g.0;
+            if let State::Ready { application_id, total_guilds_to_load, guilds } = state.deref_mut() {
+                let Guild { id, name, channels, .. } = g.0;
 
                 let basic_guild_info = BasicGuildInfo {
+                    id,
                     name,
-                    channels,
                 };
+                let basic_channel_infos = channels.into_iter().map(BasicChannelInfo::from).collect();
 
-                guilds.push(basic_guild_info);
+                guilds.push((basic_guild_info, basic_channel_infos));
 
                 if guilds.len() >= *total_guilds_to_load {
                     let guilds = mem::take(guilds);
                     *state = State::Done {
+                        application_id: *application_id,
                         guilds
                     };
-                    done_sender.send(()).await.unwrap();
+                    // Nobody is waiting anymore if this is a reconnect
+                    let _ = done_sender.send(()).await;
                 }
+            } else if let State::Done { .. } = state.deref() {
+                println!("Joined guild `{}` after startup, restart to build galleries from it", g.0.name);
             } else {
                 panic!("Wrong state!")
             }
         }
+        // Nothing may be watching, in which case the message events are simply dropped
+        Event::MessageCreate(message) => {
+            let _ = watch_sender.send(WatchEvent::MessageCreated {
+                channel_id: message.channel_id,
+                message: message.0.into(),
+            });
+        }
+        Event::MessageUpdate(message_update) => {
+            let _ = watch_sender.send(WatchEvent::MessageUpdated {
+                channel_id: message_update.channel_id,
+                message_id: message_update.id,
+            });
+        }
+        Event::MessageDelete(message_delete) => {
+            let _ = watch_sender.send(WatchEvent::MessagesDeleted {
+                channel_id: message_delete.channel_id,
+                message_ids: vec![message_delete.id],
+            });
+        }
+        Event::MessageDeleteBulk(message_delete_bulk) => {
+            let _ = watch_sender.send(WatchEvent::MessagesDeleted {
+                channel_id: message_delete_bulk.channel_id,
+                message_ids: message_delete_bulk.ids,
+            });
+        }
+        Event::InteractionCreate(interaction) => {
+            handle_interaction(&http, interaction.0, &watch_sender).await?;
+        }
         _ => {}
     }
 
     Ok(())
 }
 
-fn is_attachment_image(attachment: &Attachment) -> bool {
-    attachment.content_type.is_some() && attachment.content_type.as_ref().unwrap().starts_with("image")
+async fn read_user_line(reader: &mut tokio::io::BufReader<tokio::io::Stdin>) -> String {
+    let mut read_buffer = String::new();
+    reader.read_line(&mut read_buffer).await.unwrap();
+    read_buffer.trim().to_owned()
 }
 
-async fn ask_user_for_guild_channel(basic_guild_infos: Vec<BasicGuildInfo>, http: Arc<HttpClient>) {
-    let mut reader = tokio::io::BufReader::new(tokio::io::stdin());
+/// Returns true if `choice` is either the ID or the (case insensitive) name of something
+fn matches_name_or_id(choice: &str, id: u64, name: Option<&str>) -> bool {
+    u64::from_str(choice).is_ok_and(|choice_id| choice_id == id)
+        || name.is_some_and(|name| name.eq_ignore_ascii_case(choice))
+}
+
+async fn choose_guild<'a>(
+    basic_guild_infos: &'a [BasicGuildInfo],
+    guild_choice: Option<&str>,
+    reader: &mut tokio::io::BufReader<tokio::io::Stdin>,
+) -> Result<Option<&'a BasicGuildInfo>, Box<dyn Error + Send + Sync>> {
+    if let Some(guild_choice) = guild_choice {
+        return match basic_guild_infos.iter().find(|guild| matches_name_or_id(guild_choice, guild.id.get(), Some(&guild.name))) {
+            Some(guild) => Ok(Some(guild)),
+            None => Err(format!("No guild with the name or ID `{guild_choice}`").into()),
+        };
+    }
 
-    let chosen_guild_index = loop {
+    loop {
         println!("Select guild to generate gallery.");
         for (i, guild) in basic_guild_infos.iter().enumerate() {
             println!("{: >2}) {}", i, guild.name)
@@ -166,168 +284,109 @@ async fn ask_user_for_guild_channel(basic_guild_infos: Vec<BasicGuildInfo>, http
         print!("Enter guild to use (q to quit): ");
         io::stdout().flush().unwrap();
 
-        let mut read_buffer = Vec::new();
-        reader.read_until(b'\n', &mut read_buffer).await.unwrap();
-        let guild_number_input_string = String::from_utf8(read_buffer.clone()).unwrap();
-        if guild_number_input_string.trim() == "q" {
-            return;
+        let guild_number_input_string = read_user_line(reader).await;
+        if guild_number_input_string == "q" {
+            return Ok(None);
         }
-        if let Ok(guild_number) = usize::from_str(guild_number_input_string.trim()) {
-            break guild_number;
+        if let Some(guild) = usize::from_str(&guild_number_input_string).ok().and_then(|guild_number| basic_guild_infos.get(guild_number)) {
+            println!();
+            return Ok(Some(guild));
         } else {
             println!("Invalid choice: {}", guild_number_input_string);
         }
-    };
-    println!();
-
-    let chosen_guild = &basic_guild_infos[chosen_guild_index];
-    let guild_categories = chosen_guild.channels.iter().filter(|c| c.kind == ChannelType::GuildCategory);
-    let mut valid_guild_categories = guild_categories.filter(|guild_category| {
-        chosen_guild.channels.iter().any(|guild_channel| {
-            guild_channel.parent_id == Some(guild_category.id) && guild_channel.kind == ChannelType::GuildText
-        })
-    });
-
-    let chosen_category_index = loop {
-        for (i, guild_category) in valid_guild_categories.clone().enumerate() {
+    }
+}
+
+async fn choose_category<'a>(
+    chosen_guild: &BasicGuildInfo,
+    guild_channels: &'a [BasicChannelInfo],
+    category_choice: Option<&str>,
+    reader: &mut tokio::io::BufReader<tokio::io::Stdin>,
+) -> Result<Option<&'a BasicChannelInfo>, Box<dyn Error + Send + Sync>> {
+    let valid_guild_categories = gallery_categories(guild_channels);
+
+    if let Some(category_choice) = category_choice {
+        return match valid_guild_categories.into_iter().find(|category| matches_name_or_id(category_choice, category.id.get(), category.name.as_deref())) {
+            Some(category) => Ok(Some(category)),
+            None => Err(format!("No category with text channels with the name or ID `{category_choice}` in guild `{}`", chosen_guild.name).into()),
+        };
+    }
+
+    loop {
+        for (i, guild_category) in valid_guild_categories.iter().enumerate() {
             println!("{: >2}) {}", i, &guild_category.name.as_deref().unwrap_or("No Category Name"))
         }
 
-        print!("Enter guild category to use: ");
+        print!("Enter guild category to use (q to quit): ");
         io::stdout().flush().unwrap();
-        let mut read_buffer = String::new();
-        reader.read_line(&mut read_buffer).await.unwrap();
-        if let Ok(category_index) = usize::from_str(read_buffer.trim()) {
-            break category_index;
-        } else {
-            println!("Invalid choice: {}", read_buffer);
+        let category_input_string = read_user_line(reader).await;
+        if category_input_string == "q" {
+            return Ok(None);
         }
-    };
-    println!();
-
-    let chosen_category = valid_guild_categories.nth(chosen_category_index).unwrap();
-
-    let category_channels = chosen_guild.channels.iter().filter(|c| c.parent_id == Some(chosen_category.id) && c.kind == ChannelType::GuildText);
-    // let category_names = category_channels.map(|c| c.name.as_ref().unwrap()).collect::<Vec<_>>();
-    // println!("Guild category `{}` with channels: {:?}", chosen_category.name.as_ref().unwrap(), category_names);
-
-    let mut galleries = Vec::new();
-
-    let thumbnail_downloader = Arc::new(std::sync::Mutex::new(ThumbnailDownloader::new()));
-
-    for channel in category_channels {
-        let channel_messages = http.channel_messages(channel.id).await.unwrap().model().await.unwrap();
-
-        if channel_messages.is_empty() {
-            continue;
+        if let Some(category) = usize::from_str(&category_input_string).ok().and_then(|category_index| valid_guild_categories.get(category_index)) {
+            println!();
+            return Ok(Some(category));
+        } else {
+            println!("Invalid choice: {}", category_input_string);
         }
+    }
+}
 
-        let author_discord_name = {
-            let mut counts = BTreeMap::new();
-            for message in channel_messages.iter() {
-                if message.attachments.iter().any(is_attachment_image) {
-                    *counts.entry(&message.author.name).or_insert(0) += 1;
-                }
-            }
-
-            if counts.is_empty() {
-                continue;
-            }
-
-            let max = counts.into_iter().max_by_key(|&(_, count)| count).unwrap();
-            max.0.clone()
-        };
-
-        let gallery_picture_infos = channel_messages
-            .into_iter()
-            .rev()
-            .flat_map(|message| {
-                let picture_description = if message.content.is_empty() {
-                    None
-                } else {
-                    Some(message.content.clone())
-                };
-                let thumbnail_downloader = thumbnail_downloader.clone();
-                message
-                    .attachments
-                    .into_iter()
-                    .filter(is_attachment_image)
-                    .map(move |attachment| {
-                        let picture_description = picture_description.clone();
-                        let discord_url = attachment.proxy_url;
-                        let thumbnail_url = thumbnail_downloader.lock().unwrap().queue_download("test_website", &discord_url);
-                        GalleryPictureInfo {
-                            picture_description,
-                            discord_url,
-                            thumbnail_url,
-                        }
-                    })
-            }).collect::<Vec<_>>();
-
-        let author_name_channel = parse_author_name_from_channel_name(channel.name.as_deref().unwrap_or("No channel name?"), ChannelParseMode::FirstFullLastInitial);
-
-        let gallery_title = format!("{author_name_channel} ({author_discord_name})");
-
-        galleries.push(
-            Gallery {
-                gallery_title,
-                gallery_picture_infos,
-            }
-        );
+/// Works out which guild categories to build galleries from, either from the config file or the command line
+async fn choose_build_targets(source: &dyn MessageSource, config: Option<&GalleryConfig>, cli: &Cli) -> Result<Vec<GalleryBuildTarget>, Box<dyn Error + Send + Sync>> {
+    let mut build_targets = match config {
+        Some(config) => config_build_targets(source, config, cli).await?,
+        None => choose_cli_build_target(source, cli).await?.into_iter().collect(),
+    };
+    for build_target in &mut build_targets {
+        build_target.build_options.website_root = resolve_website_root(&build_target.build_options.website_root).map_err(|err| err.to_string())?;
     }
 
-    galleries.sort_unstable_by(|g1, g2| g1.gallery_title.cmp(&g2.gallery_title));
+    Ok(build_targets)
+}
 
-    let page_title = format!("{} Photo Galleries", chosen_guild.name);
+async fn choose_cli_build_target(source: &dyn MessageSource, cli: &Cli) -> Result<Option<GalleryBuildTarget>, Box<dyn Error + Send + Sync>> {
+    let mut reader = tokio::io::BufReader::new(tokio::io::stdin());
 
-    let gallery_page_info = GalleryPageInfo {
-        page_title,
-        galleries,
-        guild_built_from: chosen_guild.name.clone(),
-        page_built_time: "PAGE BUILT TIME".to_string(),
+    let basic_guild_infos = source.guilds().await?;
+    let Some(chosen_guild) = choose_guild(&basic_guild_infos, cli.guild.as_deref(), &mut reader).await? else {
+        return Ok(None);
+    };
+    let guild_channels = source.guild_channels(chosen_guild.id).await?;
+    let Some(chosen_category) = choose_category(chosen_guild, &guild_channels, cli.category.as_deref(), &mut reader).await? else {
+        return Ok(None);
     };
 
-    let rendered_page = render_page(&gallery_page_info);
-    write_whole_website_directory("test_website", &rendered_page);
-
-    {
-        let g = Arc::try_unwrap(thumbnail_downloader).unwrap_or_else(|_| panic!("")).into_inner().unwrap();
-        g.download_all().await;
-    }
-}
-
-pub enum ChannelParseMode {
-    FullName,
-    FirstFullLastInitial,
+    Ok(Some(GalleryBuildTarget {
+        guild: chosen_guild.clone(),
+        category: chosen_category.clone(),
+        guild_channels: guild_channels.clone(),
+        build_options: cli.build_options(),
+    }))
 }
 
-fn parse_author_name_from_channel_name(channel_name: &str, channel_parse_mode: ChannelParseMode) -> String {
-    match channel_parse_mode {
-        ChannelParseMode::FullName => {
-            channel_name
-                .split('-')
-                .map(|s| {
-                    let mut chars = s.chars();
-                    let mut string = String::from(chars.next().unwrap().to_ascii_uppercase());
-                    string += &*chars.as_str().to_ascii_lowercase();
-
-                    string
-                })
-                .collect::<Vec<String>>()
-                .join(" ")
-        }
-        ChannelParseMode::FirstFullLastInitial => {
-            let channel_name_parts = channel_name.split('-').collect::<Vec<&str>>();
-            return if channel_name_parts.len() >= 2 {
-                let mut first_name_chars = channel_name_parts[0].chars();
-                let first_initial = first_name_chars.next().unwrap().to_ascii_uppercase();
-                let rest_of_first_name = first_name_chars.as_str().to_ascii_lowercase();
-                let last_initial = channel_name_parts[1].chars().next().unwrap().to_ascii_uppercase();
-                format!("{}{} {}.", first_initial, rest_of_first_name, last_initial)
-            } else { // If there isn't 2 parts to the name just return the channel name, this means someone didn't name their channel right (Shame!)
-                channel_name.to_owned()
-            };
-        }
+async fn config_build_targets(source: &dyn MessageSource, config: &GalleryConfig, cli: &Cli) -> Result<Vec<GalleryBuildTarget>, Box<dyn Error + Send + Sync>> {
+    let basic_guild_infos = source.guilds().await?;
+
+    let mut build_targets = Vec::new();
+    for (profile_name, profile) in config.select_profiles(&cli.profiles)? {
+        let guild = basic_guild_infos.iter().find(|guild| guild.id == profile.guild)
+            .ok_or_else(|| format!("Profile `{profile_name}`: no guild with ID `{}`", profile.guild))?;
+        let guild_channels = source.guild_channels(guild.id).await?;
+        let category = guild_channels.iter().find(|channel| channel.id == profile.category && channel.kind == BasicChannelKind::Category)
+            .ok_or_else(|| format!("Profile `{profile_name}`: guild `{}` has no category with ID `{}`", guild.name, profile.category))?
+            .clone();
+
+        let mut build_options = profile.build_options();
+        build_options.full_resync = cli.full_resync;
+
+        build_targets.push(GalleryBuildTarget {
+            guild: guild.clone(),
+            category,
+            guild_channels,
+            build_options,
+        });
     }
-}
 
+    Ok(build_targets)
+}
@@ -0,0 +1,97 @@
+//! Finds pictures in a gallery that are the same shot posted more than once, such as light edits and crops of it
+
+use image::DynamicImage;
+use image::imageops::FilterType;
+
+use crate::thumbnail_download::ImageInfo;
+use crate::website::builder::gallery_page_info::GalleryPictureInfo;
+
+/// Perceptual hashes at most this many bits apart are counted as the same shot
+const MAX_HASH_DISTANCE: u32 = 10;
+/// Crops barely change the shape of a shot, so pictures whose aspect ratios differ by more than this fraction aren't
+/// grouped. This keeps plain images with next to no detail, whose hashes are all alike, apart.
+const MAX_ASPECT_RATIO_DIFFERENCE: f64 = 0.2;
+
+/// What near-duplicates are found by
+#[derive(Debug, Clone, Copy)]
+struct Fingerprint {
+    perceptual_hash: u64,
+    aspect_ratio: f64,
+}
+
+impl Fingerprint {
+    fn from_image_info(image_info: &ImageInfo) -> Option<Fingerprint> {
+        Some(Fingerprint {
+            perceptual_hash: image_info.perceptual_hash?,
+            aspect_ratio: f64::from(image_info.thumbnail_width) / f64::from(image_info.thumbnail_height.max(1)),
+        })
+    }
+
+    fn is_near_duplicate(self, other: Fingerprint) -> bool {
+        let aspect_ratio_difference = (self.aspect_ratio - other.aspect_ratio).abs() / self.aspect_ratio.min(other.aspect_ratio);
+
+        (self.perceptual_hash ^ other.perceptual_hash).count_ones() <= MAX_HASH_DISTANCE
+            && aspect_ratio_difference <= MAX_ASPECT_RATIO_DIFFERENCE
+    }
+}
+
+/// A 64 bit difference hash: whether each pixel of a 9x8 greyscale shrink of the image is brighter than the one to its
+/// right. Small edits, crops and re-encodes only flip a few of the bits.
+pub fn perceptual_hash(image: &DynamicImage) -> u64 {
+    let shrunk_image = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = shrunk_image.get_pixel(x, y)[0] > shrunk_image.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | u64::from(brighter);
+        }
+    }
+
+    hash
+}
+
+/// Collapses every group of near-duplicate pictures into its largest one, shown where the group's first picture was and
+/// counting the rest as its variants, whose pages redirect to its page. Pictures whose image info has no perceptual
+/// hash are never grouped.
+pub fn collapse_near_duplicates(pictures: Vec<(GalleryPictureInfo, Option<&ImageInfo>)>) -> Vec<GalleryPictureInfo> {
+    let fingerprints = pictures.iter()
+        .map(|(_, image_info)| image_info.and_then(Fingerprint::from_image_info))
+        .collect::<Vec<_>>();
+
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (picture_index, fingerprint) in fingerprints.iter().enumerate() {
+        let near_duplicate_group = fingerprint.and_then(|fingerprint| groups.iter_mut().find(|group| {
+            group.iter().any(|&member_index| {
+                fingerprints[member_index].is_some_and(|member_fingerprint| member_fingerprint.is_near_duplicate(fingerprint))
+            })
+        }));
+
+        match near_duplicate_group {
+            Some(group) => group.push(picture_index),
+            None => groups.push(vec![picture_index]),
+        }
+    }
+
+    let pixel_counts = pictures.iter()
+        .map(|(_, image_info)| image_info.map_or(0, |image_info| {
+            u64::from(image_info.source_width) * u64::from(image_info.source_height)
+        }))
+        .collect::<Vec<_>>();
+    let mut pictures = pictures.into_iter().map(|(picture, _)| Some(picture)).collect::<Vec<_>>();
+
+    groups
+        .into_iter()
+        .map(|group| {
+            // The first of the largest wins ties, so reposts of the same file keep the earliest one
+            let best_index = group.iter().copied().rev().max_by_key(|&picture_index| pixel_counts[picture_index]).unwrap();
+            let mut best_picture = pictures[best_index].take().unwrap();
+            best_picture.variant_count = group.len() - 1;
+            best_picture.collapsed_permalink_urls = group.iter()
+                .filter_map(|&picture_index| pictures[picture_index].take())
+                .map(|collapsed_picture| collapsed_picture.permalink_url)
+                .collect();
+            best_picture
+        })
+        .collect()
+}
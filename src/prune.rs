@@ -0,0 +1,124 @@
+//! Removes files a website no longer uses, such as the thumbnails of deleted messages and of channels that left the
+//! category
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_derive::Deserialize;
+
+use crate::cli::BYTES_PER_MIB;
+use crate::thumbnail_download::{ORIGINALS_FOLDER, THUMBNAILS_FOLDER};
+use crate::website::builder::gallery_page_info::Gallery;
+
+/// Folders of the website root that only hold downloaded images. Builds from before thumbnails were content addressed
+/// saved them under discord's url path (`attachments`). Nothing outside these is ever pruned.
+pub(crate) const DOWNLOADED_IMAGE_FOLDERS: [&str; 3] = [THUMBNAILS_FOLDER, ORIGINALS_FOLDER, "attachments"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PruneMode {
+    /// Leave unreferenced files alone
+    Keep,
+    /// Only list the files that would be removed
+    DryRun,
+    Delete,
+}
+
+/// Every file the galleries link to, relative to the website root
+pub fn referenced_files(galleries: &[Gallery]) -> HashSet<PathBuf> {
+    galleries
+        .iter()
+        .flat_map(|gallery| &gallery.gallery_picture_infos)
+        .flat_map(|gallery_picture_info| {
+            let thumbnail_urls = gallery_picture_info.thumbnail_variants
+                .iter()
+                .chain(gallery_picture_info.thumbnail_sources.iter().flat_map(|thumbnail_set| &thumbnail_set.variants))
+                .map(|thumbnail_variant| &thumbnail_variant.url);
+
+            // Full urls of originals that aren't mirrored point at discord, so they never match a file
+            [&gallery_picture_info.thumbnail_url, &gallery_picture_info.full_url].into_iter().chain(thumbnail_urls)
+        })
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Removes, or with [`PruneMode::DryRun`] lists, the downloaded images in the website root that aren't in
+/// `referenced_files`
+pub fn prune_unreferenced_files(website_root: &Path, referenced_files: &HashSet<PathBuf>, prune_mode: PruneMode) -> io::Result<()> {
+    if prune_mode == PruneMode::Keep {
+        return Ok(());
+    }
+
+    let mut unreferenced_files = Vec::new();
+    for prunable_folder in DOWNLOADED_IMAGE_FOLDERS {
+        find_unreferenced_files(website_root, &website_root.join(prunable_folder), referenced_files, &mut unreferenced_files)?;
+    }
+    unreferenced_files.sort_unstable();
+
+    let mut pruned_bytes = 0;
+    for (unreferenced_file, file_size) in &unreferenced_files {
+        pruned_bytes += file_size;
+        if prune_mode == PruneMode::DryRun {
+            println!("Would prune unreferenced `{}`", unreferenced_file.display());
+        } else {
+            println!("Pruning unreferenced `{}`", unreferenced_file.display());
+            fs::remove_file(unreferenced_file)?;
+        }
+    }
+
+    let pruned_mib = pruned_bytes as f64 / BYTES_PER_MIB as f64;
+    if prune_mode == PruneMode::DryRun {
+        println!("{} unreferenced files ({pruned_mib:.1} MiB) would be pruned", unreferenced_files.len());
+    } else {
+        for prunable_folder in DOWNLOADED_IMAGE_FOLDERS {
+            remove_empty_folders(&website_root.join(prunable_folder))?;
+        }
+        println!("Pruned {} unreferenced files ({pruned_mib:.1} MiB)", unreferenced_files.len());
+    }
+
+    Ok(())
+}
+
+/// Adds every file under `folder` that isn't referenced to `unreferenced_files`, along with its size
+fn find_unreferenced_files(website_root: &Path, folder: &Path, referenced_files: &HashSet<PathBuf>, unreferenced_files: &mut Vec<(PathBuf, u64)>) -> io::Result<()> {
+    if !folder.is_dir() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(folder)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let path = entry.path();
+        if metadata.is_dir() {
+            find_unreferenced_files(website_root, &path, referenced_files, unreferenced_files)?;
+        } else if !referenced_files.contains(path.strip_prefix(website_root).unwrap()) {
+            unreferenced_files.push((path, metadata.len()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the folders under `folder` left empty by pruning, and `folder` itself if it ends up empty. Returns whether
+/// `folder` was removed.
+fn remove_empty_folders(folder: &Path) -> io::Result<bool> {
+    if !folder.is_dir() {
+        return Ok(false);
+    }
+
+    let mut is_empty = true;
+    for entry in fs::read_dir(folder)? {
+        let entry = entry?;
+        if !(entry.file_type()?.is_dir() && remove_empty_folders(&entry.path())?) {
+            is_empty = false;
+        }
+    }
+
+    if is_empty {
+        fs::remove_dir(folder)?;
+    }
+
+    Ok(is_empty)
+}
@@ -0,0 +1,278 @@
+//! A [`MessageSource`] reading channel archives made with [DiscordChatExporter](https://github.com/Tyrrrz/DiscordChatExporter).
+//!
+//! Each JSON export holds a single channel, along with the guild and category it was in. Exports made with
+//! `--media` point their attachments at files next to the export, which are used in place of the discord CDN. Exports
+//! of direct messages aren't in a guild and exports of threads and forum posts aren't gallery channels, so they're
+//! skipped.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use chrono::DateTime;
+use serde_derive::Deserialize;
+use twilight_model::id::Id;
+use twilight_model::id::marker::{AttachmentMarker, ChannelMarker, GuildMarker, MessageMarker};
+use twilight_model::util::Timestamp;
+
+use crate::channel_history::{HistoryCursor, HistoryLimit};
+use crate::source::{apply_history_window, BasicAttachmentInfo, BasicChannelInfo, BasicChannelKind, BasicGuildInfo, BasicMessageInfo, MessageSource, resolve_local_url, SourceResult};
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChatExport {
+    guild: ExportGuild,
+    channel: ExportChannel,
+    messages: Vec<ExportMessage>,
+}
+
+/// Direct message exports are given this guild ID, which isn't a valid one
+const DIRECT_MESSAGES_GUILD_ID: &str = "0";
+
+#[derive(Debug, Deserialize)]
+struct ExportGuild {
+    /// Kept as a string, as it's [`DIRECT_MESSAGES_GUILD_ID`] for direct messages
+    id: String,
+    name: String,
+}
+
+/// Channel types of thread and forum post exports, whose category is really the channel they were started in
+const THREAD_CHANNEL_TYPES: [&str; 3] = ["GuildPublicThread", "GuildPrivateThread", "GuildNewsThread"];
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportChannel {
+    id: Id<ChannelMarker>,
+    #[serde(rename = "type")]
+    kind: String,
+    name: String,
+    category_id: Option<Id<ChannelMarker>>,
+    category: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportMessage {
+    id: Id<MessageMarker>,
+    timestamp: String,
+    #[serde(default)]
+    content: String,
+    author: ExportAuthor,
+    #[serde(default)]
+    attachments: Vec<ExportAttachment>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportAuthor {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportAttachment {
+    id: Id<AttachmentMarker>,
+    url: String,
+    file_name: String,
+    file_size_bytes: Option<u64>,
+}
+
+/// A single loaded export, with its messages already converted
+struct ExportedChannel {
+    guild: BasicGuildInfo,
+    channel: BasicChannelInfo,
+    category: Option<BasicChannelInfo>,
+    messages: Vec<BasicMessageInfo>,
+}
+
+pub struct ChatExporterSource {
+    channels: Vec<ExportedChannel>,
+}
+
+impl ChatExporterSource {
+    /// Loads a single export file, or every `.json` export in a directory
+    pub fn open<P: AsRef<Path>>(export_path: P) -> SourceResult<ChatExporterSource> {
+        let export_path = export_path.as_ref();
+        let export_files = if export_path.is_dir() {
+            let mut export_files = fs::read_dir(export_path)
+                .map_err(|err| format!("Failed to read export directory `{}`: {}", export_path.display(), err))?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<PathBuf>, _>>()?;
+            export_files.retain(|path| path.extension().is_some_and(|extension| extension == "json"));
+            export_files.sort();
+            export_files
+        } else {
+            vec![export_path.to_path_buf()]
+        };
+
+        let mut channels = Vec::new();
+        for export_file in &export_files {
+            match load_export(export_file)? {
+                Ok(exported_channel) => channels.push(exported_channel),
+                Err(skip_reason) => eprintln!("Skipping export `{}`, it's of {skip_reason}, which can't be a gallery", export_file.display()),
+            }
+        }
+        if channels.is_empty() {
+            return Err(format!("No exports of guild channels found in `{}`", export_path.display()).into());
+        }
+
+        Ok(ChatExporterSource {
+            channels,
+        })
+    }
+}
+
+/// Loads an export of a guild channel, or says what else it's an export of
+fn load_export(export_file: &Path) -> SourceResult<Result<ExportedChannel, &'static str>> {
+    let json = fs::read_to_string(export_file).map_err(|err| format!("Failed to read export `{}`: {}", export_file.display(), err))?;
+    let export: ChatExport = serde_json::from_str(&json).map_err(|err| format!("Failed to parse export `{}`: {}", export_file.display(), err))?;
+    if export.guild.id == DIRECT_MESSAGES_GUILD_ID {
+        return Ok(Err("direct messages"));
+    }
+    if THREAD_CHANNEL_TYPES.contains(&export.channel.kind.as_str()) {
+        return Ok(Err("a thread or forum post"));
+    }
+    let guild_id = Id::<GuildMarker>::from_str(&export.guild.id)
+        .map_err(|err| format!("Export `{}` has an invalid guild ID `{}`: {}", export_file.display(), export.guild.id, err))?;
+
+    // Media downloaded along with the export is referenced relative to the export file
+    let export_directory = export_file.canonicalize()?.parent().map(Path::to_path_buf).unwrap_or_default();
+
+    let messages = export.messages
+        .into_iter()
+        .map(|message| {
+            let timestamp = DateTime::parse_from_rfc3339(&message.timestamp)
+                .map_err(|err| format!("Message `{}` in export `{}` has an invalid timestamp: {}", message.id, export_file.display(), err))?;
+
+            Ok(BasicMessageInfo {
+                id: message.id,
+                author_name: message.author.name,
+                content: message.content,
+                timestamp: Timestamp::from_micros(timestamp.timestamp_micros())?,
+                attachments: message.attachments.into_iter().map(|attachment| {
+                    let url = resolve_local_url(&export_directory, &attachment.url);
+                    BasicAttachmentInfo {
+                        id: attachment.id,
+                        content_type: content_type_from_file_name(&attachment.file_name),
+                        filename: attachment.file_name,
+                        proxy_url: url.clone(),
+                        url,
+                        size: attachment.file_size_bytes,
+                        // Exports don't record image dimensions
+                        width: None,
+                        height: None,
+                    }
+                }).collect(),
+            })
+        })
+        .collect::<SourceResult<Vec<_>>>()?;
+
+    let category = export.channel.category_id.map(|category_id| BasicChannelInfo {
+        id: category_id,
+        name: export.channel.category.clone(),
+        kind: BasicChannelKind::Category,
+        parent_id: None,
+    });
+
+    Ok(Ok(ExportedChannel {
+        guild: BasicGuildInfo {
+            id: guild_id,
+            name: export.guild.name,
+        },
+        channel: BasicChannelInfo {
+            id: export.channel.id,
+            name: Some(export.channel.name),
+            kind: BasicChannelKind::Text,
+            parent_id: export.channel.category_id,
+        },
+        category,
+        messages,
+    }))
+}
+
+/// Exports don't record content types, so guess them from the file extension like a browser would
+fn content_type_from_file_name(file_name: &str) -> Option<String> {
+    let extension = Path::new(file_name).extension()?.to_str()?.to_ascii_lowercase();
+    let image_type = match extension.as_str() {
+        "jpg" | "jpeg" => "jpeg",
+        "png" | "gif" | "webp" | "bmp" | "avif" | "tiff" => &extension,
+        _ => return None,
+    };
+
+    Some(format!("image/{image_type}"))
+}
+
+#[async_trait]
+impl MessageSource for ChatExporterSource {
+    async fn guilds(&self) -> SourceResult<Vec<BasicGuildInfo>> {
+        let mut guilds: Vec<BasicGuildInfo> = Vec::new();
+        for exported_channel in &self.channels {
+            if !guilds.iter().any(|guild| guild.id == exported_channel.guild.id) {
+                guilds.push(exported_channel.guild.clone());
+            }
+        }
+
+        Ok(guilds)
+    }
+
+    async fn guild_channels(&self, guild_id: Id<GuildMarker>) -> SourceResult<Vec<BasicChannelInfo>> {
+        let mut channels: Vec<BasicChannelInfo> = Vec::new();
+        for exported_channel in self.channels.iter().filter(|exported_channel| exported_channel.guild.id == guild_id) {
+            let exported_channels = exported_channel.category.iter().chain([&exported_channel.channel]);
+            for channel in exported_channels {
+                if !channels.iter().any(|c| c.id == channel.id) {
+                    channels.push(channel.clone());
+                }
+            }
+        }
+
+        Ok(channels)
+    }
+
+    async fn channel_messages(&self, channel_id: Id<ChannelMarker>, cursor: HistoryCursor, limit: &HistoryLimit) -> SourceResult<Vec<BasicMessageInfo>> {
+        // The same channel may have been exported more than once, so merge every export of it
+        let mut messages_by_id = BTreeMap::new();
+        for exported_channel in self.channels.iter().filter(|exported_channel| exported_channel.channel.id == channel_id) {
+            for message in &exported_channel.messages {
+                messages_by_id.entry(message.id).or_insert_with(|| message.clone());
+            }
+        }
+        let mut messages = messages_by_id.into_values().collect();
+
+        apply_history_window(&mut messages, cursor, limit);
+
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn export_json(channel_id: u64, channel_type: &str, category_id: u64) -> String {
+        format!(r#"{{
+            "guild": {{ "id": "100000000000000000", "name": "Demo Photo Club" }},
+            "channel": {{ "id": "{channel_id}", "type": "{channel_type}", "name": "jane-doe", "categoryId": "{category_id}", "category": "Member Galleries" }},
+            "messages": []
+        }}"#)
+    }
+
+    #[tokio::test]
+    async fn skips_thread_exports() {
+        let export_folder = std::env::temp_dir().join(format!("discord_photo_gallery_exports_{}", std::process::id()));
+        fs::create_dir_all(&export_folder).unwrap();
+        fs::write(export_folder.join("channel.json"), export_json(200000000000000001, "GuildTextChat", 200000000000000000)).unwrap();
+        // Threads give the channel they were started in as their category
+        fs::write(export_folder.join("thread.json"), export_json(200000000000000002, "GuildPublicThread", 200000000000000001)).unwrap();
+
+        let source = ChatExporterSource::open(&export_folder);
+        fs::remove_dir_all(&export_folder).unwrap();
+        let source = source.unwrap();
+
+        let guilds = source.guilds().await.unwrap();
+        let channels = source.guild_channels(guilds[0].id).await.unwrap();
+        let channel_kinds = channels.iter().map(|channel| (channel.id.get(), channel.kind)).collect::<Vec<_>>();
+        assert_eq!(channel_kinds, [(200000000000000000, BasicChannelKind::Category), (200000000000000001, BasicChannelKind::Text)]);
+    }
+}
@@ -0,0 +1,133 @@
+//! An offline [`MessageSource`] that reads guilds and messages out of a directory of JSON files.
+//!
+//! The directory is laid out as:
+//!
+//! ```text
+//! fixtures/
+//!   guilds.json                 [{ "id": "1", "name": "Guild", "channels": [{ "id": "2", "name": "photos", "kind": "category" }, ...] }]
+//!   messages/<channel id>.json  [{ "id": "3", "author_name": "someone", "timestamp": "2023-01-01T00:00:00+00:00", "attachments": [...] }]
+//! ```
+//!
+//! A channel without a messages file has no messages. Attachment urls without a scheme are treated as paths
+//! relative to the fixture directory, so the images can live next to the fixtures. Their thumbnails are named by
+//! content hash, but mirrored originals of local images all go into `originals/local/`, so mirrored images need unique
+//! file names.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use serde_derive::Deserialize;
+use twilight_model::id::Id;
+use twilight_model::id::marker::{ChannelMarker, GuildMarker};
+
+use crate::channel_history::{HistoryCursor, HistoryLimit};
+use crate::source::{apply_history_window, BasicChannelInfo, BasicGuildInfo, BasicMessageInfo, MessageSource, resolve_local_url, SourceResult};
+
+#[derive(Debug, Deserialize)]
+struct FixtureGuild {
+    #[serde(flatten)]
+    guild: BasicGuildInfo,
+    #[serde(default)]
+    channels: Vec<BasicChannelInfo>,
+}
+
+pub struct FixtureSource {
+    fixture_directory: PathBuf,
+    guilds: Vec<FixtureGuild>,
+}
+
+impl FixtureSource {
+    pub fn open<P: AsRef<Path>>(fixture_directory: P) -> SourceResult<FixtureSource> {
+        let fixture_directory = fixture_directory.as_ref().canonicalize()
+            .map_err(|err| format!("Failed to open fixture directory `{}`: {}", fixture_directory.as_ref().display(), err))?;
+        let guilds = read_json(&fixture_directory.join("guilds.json"))?;
+
+        Ok(FixtureSource {
+            fixture_directory,
+            guilds,
+        })
+    }
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> SourceResult<T> {
+    let json = fs::read_to_string(path).map_err(|err| format!("Failed to read fixture `{}`: {}", path.display(), err))?;
+    let value = serde_json::from_str(&json).map_err(|err| format!("Failed to parse fixture `{}`: {}", path.display(), err))?;
+
+    Ok(value)
+}
+
+#[async_trait]
+impl MessageSource for FixtureSource {
+    async fn guilds(&self) -> SourceResult<Vec<BasicGuildInfo>> {
+        Ok(self.guilds.iter().map(|fixture_guild| fixture_guild.guild.clone()).collect())
+    }
+
+    async fn guild_channels(&self, guild_id: Id<GuildMarker>) -> SourceResult<Vec<BasicChannelInfo>> {
+        match self.guilds.iter().find(|fixture_guild| fixture_guild.guild.id == guild_id) {
+            Some(fixture_guild) => Ok(fixture_guild.channels.clone()),
+            None => Err(format!("No fixture guild with ID `{guild_id}`").into()),
+        }
+    }
+
+    async fn channel_messages(&self, channel_id: Id<ChannelMarker>, cursor: HistoryCursor, limit: &HistoryLimit) -> SourceResult<Vec<BasicMessageInfo>> {
+        let messages_path = self.fixture_directory.join("messages").join(format!("{channel_id}.json"));
+        if !messages_path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut messages: Vec<BasicMessageInfo> = read_json(&messages_path)?;
+        apply_history_window(&mut messages, cursor, limit);
+
+        for attachment in messages.iter_mut().flat_map(|message| message.attachments.iter_mut()) {
+            attachment.url = resolve_local_url(&self.fixture_directory, &attachment.url);
+            attachment.proxy_url = resolve_local_url(&self.fixture_directory, &attachment.proxy_url);
+        }
+
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn demo_fixtures() -> FixtureSource {
+        FixtureSource::open(Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/demo")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn loads_demo_guild_and_channels() {
+        let source = demo_fixtures();
+
+        let guilds = source.guilds().await.unwrap();
+        assert_eq!(guilds.len(), 1);
+        assert_eq!(guilds[0].name, "Demo Photo Club");
+
+        let channels = source.guild_channels(guilds[0].id).await.unwrap();
+        let channel_names = channels.iter().filter_map(|channel| channel.name.as_deref()).collect::<Vec<_>>();
+        assert_eq!(channel_names, ["Member Galleries", "jane-doe", "john-smith", "general"]);
+    }
+
+    #[tokio::test]
+    async fn loads_demo_messages_with_local_images() {
+        let source = demo_fixtures();
+
+        let messages = source.channel_messages(Id::new(200000000000000002), HistoryCursor::Latest, &HistoryLimit::default()).await.unwrap();
+        let message_ids = messages.iter().map(|message| message.id.get()).collect::<Vec<_>>();
+        assert_eq!(message_ids, [300000000000000002, 300000000000000001]);
+
+        for attachment in messages.iter().flat_map(|message| &message.attachments) {
+            let image_path = reqwest::Url::parse(&attachment.url).unwrap().to_file_path().unwrap();
+            assert!(image_path.is_file(), "`{}` doesn't exist", image_path.display());
+        }
+    }
+
+    #[tokio::test]
+    async fn channel_without_messages_file_is_empty() {
+        let source = demo_fixtures();
+
+        let messages = source.channel_messages(Id::new(200000000000000004), HistoryCursor::Latest, &HistoryLimit::default()).await.unwrap();
+        assert!(messages.is_empty());
+    }
+}
@@ -0,0 +1,234 @@
+use std::cmp::Reverse;
+use std::error::Error;
+use std::path::Path;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use reqwest::Url;
+use serde_derive::{Deserialize, Serialize};
+use twilight_model::channel::ChannelType;
+use twilight_model::id::Id;
+use twilight_model::id::marker::{AttachmentMarker, ChannelMarker, GuildMarker, MessageMarker};
+use twilight_model::util::Timestamp;
+
+use crate::channel_history::{HistoryCursor, HistoryLimit};
+
+pub mod twilight;
+pub mod fixture;
+pub mod chat_exporter;
+
+pub type SourceResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// Somewhere guilds, their channels and the messages in those channels can be read from
+#[async_trait]
+pub trait MessageSource: Send + Sync {
+    async fn guilds(&self) -> SourceResult<Vec<BasicGuildInfo>>;
+
+    async fn guild_channels(&self, guild_id: Id<GuildMarker>) -> SourceResult<Vec<BasicChannelInfo>>;
+
+    /// Returns the messages of a channel newest first, see [`crate::channel_history::fetch_channel_history`]
+    async fn channel_messages(&self, channel_id: Id<ChannelMarker>, cursor: HistoryCursor, limit: &HistoryLimit) -> SourceResult<Vec<BasicMessageInfo>>;
+
+    /// Fetches a single message again for fresh attachment urls, as discord's expire. Sources whose urls never expire
+    /// return nothing.
+    async fn refetch_message(&self, _channel_id: Id<ChannelMarker>, _message_id: Id<MessageMarker>) -> SourceResult<Option<BasicMessageInfo>> {
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BasicGuildInfo {
+    pub id: Id<GuildMarker>,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BasicChannelKind {
+    Category,
+    Text,
+    Other,
+}
+
+impl From<ChannelType> for BasicChannelKind {
+    fn from(channel_type: ChannelType) -> Self {
+        match channel_type {
+            ChannelType::GuildCategory => BasicChannelKind::Category,
+            ChannelType::GuildText => BasicChannelKind::Text,
+            _ => BasicChannelKind::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BasicChannelInfo {
+    pub id: Id<ChannelMarker>,
+    pub name: Option<String>,
+    pub kind: BasicChannelKind,
+    #[serde(default)]
+    pub parent_id: Option<Id<ChannelMarker>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BasicMessageInfo {
+    pub id: Id<MessageMarker>,
+    pub author_name: String,
+    #[serde(default)]
+    pub content: String,
+    pub timestamp: Timestamp,
+    #[serde(default)]
+    pub attachments: Vec<BasicAttachmentInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BasicAttachmentInfo {
+    pub id: Id<AttachmentMarker>,
+    pub filename: String,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    pub url: String,
+    pub proxy_url: String,
+    /// Size of the file in bytes, if known
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// Dimensions of the image in pixels, if known
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+}
+
+impl BasicAttachmentInfo {
+    pub fn is_image(&self) -> bool {
+        self.content_type.as_ref().is_some_and(|content_type| content_type.starts_with("image"))
+    }
+
+    /// Whether discord's signed urls of the attachment have expired, or will within [`URL_EXPIRY_MARGIN_SECS`]
+    pub fn urls_expired(&self) -> bool {
+        [&self.url, &self.proxy_url].into_iter().any(|url| url_expires_at(url).is_some_and(|expires_at| expires_at <= Utc::now().timestamp() + URL_EXPIRY_MARGIN_SECS))
+    }
+}
+
+/// Attachment urls expiring this soon are treated as expired already, so they don't run out while the build downloads
+const URL_EXPIRY_MARGIN_SECS: i64 = 60 * 60;
+
+/// When a signed discord CDN url expires, as a unix timestamp. Discord gives it in hex in the `ex` query parameter.
+fn url_expires_at(url: &str) -> Option<i64> {
+    let url = Url::parse(url).ok()?;
+    let (_, expires_at) = url.query_pairs().find(|(name, _)| name == "ex")?;
+
+    i64::from_str_radix(&expires_at, 16).ok()
+}
+
+/// Returns the categories of a guild that contain at least one text channel
+pub fn gallery_categories(guild_channels: &[BasicChannelInfo]) -> Vec<&BasicChannelInfo> {
+    guild_channels.iter()
+        .filter(|c| c.kind == BasicChannelKind::Category)
+        .filter(|guild_category| {
+            guild_channels.iter().any(|guild_channel| {
+                guild_channel.parent_id == Some(guild_category.id) && guild_channel.kind == BasicChannelKind::Text
+            })
+        })
+        .collect()
+}
+
+/// Narrows down a channel's full message list the same way walking its history from discord would
+pub(crate) fn apply_history_window(messages: &mut Vec<BasicMessageInfo>, cursor: HistoryCursor, limit: &HistoryLimit) {
+    messages.retain(|message| match cursor {
+        HistoryCursor::Latest => true,
+        HistoryCursor::Before(message_id) => message.id < message_id,
+        HistoryCursor::After(message_id) => message.id > message_id,
+    });
+    if let Some(oldest_message_time) = limit.oldest_message_time {
+        messages.retain(|message| message.timestamp.as_micros() >= oldest_message_time.timestamp_micros());
+    }
+
+    // Match discord, which hands out the messages closest to the cursor first
+    match cursor {
+        HistoryCursor::Latest | HistoryCursor::Before(_) => messages.sort_unstable_by_key(|message| Reverse(message.id)),
+        HistoryCursor::After(_) => messages.sort_unstable_by_key(|message| message.id),
+    }
+    if let Some(max_messages) = limit.max_messages {
+        messages.truncate(max_messages);
+    }
+    messages.sort_unstable_by_key(|message| Reverse(message.id));
+}
+
+/// Turns a url without a scheme into a `file://` url relative to `base_directory`, other urls are left alone
+pub(crate) fn resolve_local_url(base_directory: &Path, url: &str) -> String {
+    if Url::parse(url).is_ok() {
+        return url.to_owned();
+    }
+
+    Url::from_file_path(base_directory.join(url)).map(String::from).unwrap_or_else(|_| url.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    /// Messages 1 to 5, each posted a day after the one before it
+    fn messages() -> Vec<BasicMessageInfo> {
+        (1..=5)
+            .map(|day| BasicMessageInfo {
+                id: Id::new(day),
+                author_name: "someone".to_owned(),
+                content: String::new(),
+                timestamp: Timestamp::from_secs(Utc.with_ymd_and_hms(2023, 1, day as u32, 12, 0, 0).unwrap().timestamp()).unwrap(),
+                attachments: Vec::new(),
+            })
+            .collect()
+    }
+
+    fn message_ids(messages: &[BasicMessageInfo]) -> Vec<u64> {
+        messages.iter().map(|message| message.id.get()).collect()
+    }
+
+    #[test]
+    fn history_window_is_newest_first() {
+        let mut messages = messages();
+        apply_history_window(&mut messages, HistoryCursor::Latest, &HistoryLimit::default());
+
+        assert_eq!(message_ids(&messages), [5, 4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn history_window_starts_at_cursor() {
+        let mut before_messages = messages();
+        apply_history_window(&mut before_messages, HistoryCursor::Before(Id::new(3)), &HistoryLimit::default());
+        let mut after_messages = messages();
+        apply_history_window(&mut after_messages, HistoryCursor::After(Id::new(3)), &HistoryLimit::default());
+
+        assert_eq!(message_ids(&before_messages), [2, 1]);
+        assert_eq!(message_ids(&after_messages), [5, 4]);
+    }
+
+    #[test]
+    fn history_window_keeps_messages_closest_to_cursor() {
+        let limit = HistoryLimit {
+            max_messages: Some(2),
+            oldest_message_time: None,
+        };
+        let mut latest_messages = messages();
+        apply_history_window(&mut latest_messages, HistoryCursor::Latest, &limit);
+        let mut after_messages = messages();
+        apply_history_window(&mut after_messages, HistoryCursor::After(Id::new(1)), &limit);
+
+        assert_eq!(message_ids(&latest_messages), [5, 4]);
+        assert_eq!(message_ids(&after_messages), [3, 2]);
+    }
+
+    #[test]
+    fn history_window_drops_messages_before_oldest_time() {
+        let limit = HistoryLimit {
+            max_messages: None,
+            oldest_message_time: Some(Utc.with_ymd_and_hms(2023, 1, 3, 0, 0, 0).unwrap()),
+        };
+        let mut messages = messages();
+        apply_history_window(&mut messages, HistoryCursor::Latest, &limit);
+
+        assert_eq!(message_ids(&messages), [5, 4, 3]);
+    }
+}
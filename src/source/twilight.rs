@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use twilight_http::Client as HttpClient;
+use twilight_model::channel::{Attachment, Channel, Message};
+use twilight_model::id::Id;
+use twilight_model::id::marker::{ApplicationMarker, ChannelMarker, GuildMarker, MessageMarker};
+
+use crate::channel_history::{fetch_channel_history, HistoryCursor, HistoryLimit};
+use crate::source::{BasicAttachmentInfo, BasicChannelInfo, BasicGuildInfo, BasicMessageInfo, MessageSource, SourceResult};
+
+/// Reads from discord, using the guilds the gateway told us about and the http api for everything else
+pub struct TwilightSource {
+    http: Arc<HttpClient>,
+    application_id: Id<ApplicationMarker>,
+    guilds: Vec<(BasicGuildInfo, Vec<BasicChannelInfo>)>,
+}
+
+impl TwilightSource {
+    pub fn new(http: Arc<HttpClient>, application_id: Id<ApplicationMarker>, guilds: Vec<(BasicGuildInfo, Vec<BasicChannelInfo>)>) -> TwilightSource {
+        TwilightSource {
+            http,
+            application_id,
+            guilds,
+        }
+    }
+
+    pub fn http(&self) -> &HttpClient {
+        &self.http
+    }
+
+    /// ID of the bot's application, which its slash commands are registered under
+    pub fn application_id(&self) -> Id<ApplicationMarker> {
+        self.application_id
+    }
+
+    /// Fetches a single message, for when the gateway only says it changed
+    pub async fn message(&self, channel_id: Id<ChannelMarker>, message_id: Id<MessageMarker>) -> SourceResult<BasicMessageInfo> {
+        let message = self.http.message(channel_id, message_id).await?.model().await?;
+
+        Ok(message.into())
+    }
+}
+
+#[async_trait]
+impl MessageSource for TwilightSource {
+    async fn guilds(&self) -> SourceResult<Vec<BasicGuildInfo>> {
+        Ok(self.guilds.iter().map(|(guild, _)| guild.clone()).collect())
+    }
+
+    async fn guild_channels(&self, guild_id: Id<GuildMarker>) -> SourceResult<Vec<BasicChannelInfo>> {
+        match self.guilds.iter().find(|(guild, _)| guild.id == guild_id) {
+            Some((_, channels)) => Ok(channels.clone()),
+            None => Err(format!("Bot is not in a guild with ID `{guild_id}`").into()),
+        }
+    }
+
+    async fn channel_messages(&self, channel_id: Id<ChannelMarker>, cursor: HistoryCursor, limit: &HistoryLimit) -> SourceResult<Vec<BasicMessageInfo>> {
+        let messages = fetch_channel_history(&self.http, channel_id, cursor, limit).await?;
+
+        Ok(messages.into_iter().map(BasicMessageInfo::from).collect())
+    }
+
+    async fn refetch_message(&self, channel_id: Id<ChannelMarker>, message_id: Id<MessageMarker>) -> SourceResult<Option<BasicMessageInfo>> {
+        Ok(Some(self.message(channel_id, message_id).await?))
+    }
+}
+
+impl From<Channel> for BasicChannelInfo {
+    fn from(channel: Channel) -> Self {
+        BasicChannelInfo {
+            id: channel.id,
+            name: channel.name,
+            kind: channel.kind.into(),
+            parent_id: channel.parent_id,
+        }
+    }
+}
+
+impl From<Message> for BasicMessageInfo {
+    fn from(message: Message) -> Self {
+        BasicMessageInfo {
+            id: message.id,
+            author_name: message.author.name,
+            content: message.content,
+            timestamp: message.timestamp,
+            attachments: message.attachments.into_iter().map(BasicAttachmentInfo::from).collect(),
+        }
+    }
+}
+
+impl From<Attachment> for BasicAttachmentInfo {
+    fn from(attachment: Attachment) -> Self {
+        BasicAttachmentInfo {
+            id: attachment.id,
+            filename: attachment.filename,
+            content_type: attachment.content_type,
+            url: attachment.url,
+            proxy_url: attachment.proxy_url,
+            size: Some(attachment.size),
+            width: attachment.width.and_then(|width| width.try_into().ok()),
+            height: attachment.height.and_then(|height| height.try_into().ok()),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde_derive::{Deserialize, Serialize};
+use twilight_model::id::Id;
+use twilight_model::id::marker::{ChannelMarker, MessageMarker};
+
+use crate::channel_history::{HistoryCursor, HistoryLimit};
+use crate::source::{BasicAttachmentInfo, BasicMessageInfo, MessageSource, SourceResult};
+
+/// Name of the sync state file, kept in the website root next to the page it was built into
+pub const SYNC_STATE_FILE_NAME: &str = ".gallery_sync_state.json";
+
+/// What was fetched for each channel on previous runs, so later runs only have to fetch newer messages
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    channels: BTreeMap<Id<ChannelMarker>, ChannelSyncState>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChannelSyncState {
+    /// The newest message seen in the channel, with or without pictures
+    last_message_id: Option<Id<MessageMarker>>,
+    /// Every message in the channel with pictures, newest first
+    picture_messages: Vec<BasicMessageInfo>,
+}
+
+impl SyncState {
+    /// Loads the sync state of a website, or an empty state if it was never synced
+    pub fn load<P: AsRef<Path>>(website_root: P) -> SourceResult<SyncState> {
+        let sync_state_path = website_root.as_ref().join(SYNC_STATE_FILE_NAME);
+        if !sync_state_path.exists() {
+            return Ok(SyncState::default());
+        }
+
+        let json = fs::read_to_string(&sync_state_path)?;
+        let sync_state = serde_json::from_str(&json)
+            .map_err(|err| format!("Failed to parse sync state `{}`: {}", sync_state_path.display(), err))?;
+
+        Ok(sync_state)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, website_root: P) -> SourceResult<()> {
+        fs::create_dir_all(website_root.as_ref())?;
+        fs::write(website_root.as_ref().join(SYNC_STATE_FILE_NAME), serde_json::to_string(self)?)?;
+
+        Ok(())
+    }
+
+    /// Returns the messages with pictures of a synced channel, newest first
+    pub fn picture_messages(&self, channel_id: Id<ChannelMarker>) -> &[BasicMessageInfo] {
+        self.channels.get(&channel_id).map(|channel_state| channel_state.picture_messages.as_slice()).unwrap_or_default()
+    }
+
+    /// Forgets every channel not in `channel_ids`, such as channels that were deleted or left the category
+    pub fn retain_channels(&mut self, channel_ids: &[Id<ChannelMarker>]) {
+        self.channels.retain(|channel_id, _| channel_ids.contains(channel_id));
+    }
+
+    /// Records a new or edited message of an already synced channel, returning whether its pictures changed
+    pub fn upsert_message(&mut self, channel_id: Id<ChannelMarker>, message: BasicMessageInfo) -> bool {
+        let Some(channel_state) = self.channels.get_mut(&channel_id) else {
+            return false;
+        };
+        channel_state.last_message_id = channel_state.last_message_id.max(Some(message.id));
+
+        let has_pictures = message.attachments.iter().any(BasicAttachmentInfo::is_image);
+        let existing_index = channel_state.picture_messages.iter().position(|m| m.id == message.id);
+        match (existing_index, has_pictures) {
+            (Some(existing_index), true) => {
+                let changed = channel_state.picture_messages[existing_index] != message;
+                channel_state.picture_messages[existing_index] = message;
+                changed
+            }
+            (Some(existing_index), false) => {
+                channel_state.picture_messages.remove(existing_index);
+                true
+            }
+            (None, true) => {
+                let insert_index = channel_state.picture_messages.partition_point(|m| m.id > message.id);
+                channel_state.picture_messages.insert(insert_index, message);
+                true
+            }
+            (None, false) => false,
+        }
+    }
+
+    /// Forgets a deleted message, returning whether it had pictures
+    pub fn remove_message(&mut self, channel_id: Id<ChannelMarker>, message_id: Id<MessageMarker>) -> bool {
+        let Some(channel_state) = self.channels.get_mut(&channel_id) else {
+            return false;
+        };
+
+        let message_count = channel_state.picture_messages.len();
+        channel_state.picture_messages.retain(|message| message.id != message_id);
+        channel_state.picture_messages.len() != message_count
+    }
+
+    /// Brings a channel's messages with pictures up to date.
+    ///
+    /// Only messages newer than the last sync are fetched, unless `full_resync` is set or the channel was never
+    /// synced. A full resync refetches the whole history, which is how edits and deletions of older messages are found.
+    pub async fn sync_channel(
+        &mut self,
+        source: &dyn MessageSource,
+        channel_id: Id<ChannelMarker>,
+        history_limit: &HistoryLimit,
+        full_resync: bool,
+    ) -> SourceResult<()> {
+        let previous_state = self.channels.remove(&channel_id);
+
+        let cursor = match &previous_state {
+            Some(ChannelSyncState { last_message_id: Some(last_message_id), .. }) if !full_resync => HistoryCursor::After(*last_message_id),
+            _ => HistoryCursor::Latest,
+        };
+        let fetched_messages = source.channel_messages(channel_id, cursor, history_limit).await?;
+
+        let newest_fetched_message_id = fetched_messages.iter().map(|message| message.id).max();
+        let fetched_picture_messages = fetched_messages
+            .into_iter()
+            .filter(|message| message.attachments.iter().any(BasicAttachmentInfo::is_image))
+            .collect::<Vec<_>>();
+
+        let channel_state = match previous_state {
+            Some(mut channel_state) if !full_resync => {
+                println!("Channel `{channel_id}`: {} new messages with pictures", fetched_picture_messages.len());
+                channel_state.picture_messages.splice(0..0, fetched_picture_messages);
+                channel_state.last_message_id = channel_state.last_message_id.max(newest_fetched_message_id);
+                channel_state
+            }
+            previous_state => {
+                if let Some(previous_state) = previous_state {
+                    report_resync_changes(channel_id, &previous_state.picture_messages, &fetched_picture_messages);
+                }
+                ChannelSyncState {
+                    last_message_id: newest_fetched_message_id,
+                    picture_messages: fetched_picture_messages,
+                }
+            }
+        };
+
+        self.channels.insert(channel_id, channel_state);
+
+        Ok(())
+    }
+}
+
+fn report_resync_changes(channel_id: Id<ChannelMarker>, previous_messages: &[BasicMessageInfo], resynced_messages: &[BasicMessageInfo]) {
+    let previous_messages = previous_messages.iter().map(|message| (message.id, message)).collect::<BTreeMap<_, _>>();
+    let resynced_messages = resynced_messages.iter().map(|message| (message.id, message)).collect::<BTreeMap<_, _>>();
+
+    let new_count = resynced_messages.keys().filter(|message_id| !previous_messages.contains_key(message_id)).count();
+    let deleted_count = previous_messages.keys().filter(|message_id| !resynced_messages.contains_key(message_id)).count();
+    let edited_count = resynced_messages.iter()
+        .filter(|(message_id, message)| previous_messages.get(message_id).is_some_and(|previous_message| previous_message != *message))
+        .count();
+
+    println!("Channel `{channel_id}`: {new_count} new, {edited_count} edited and {deleted_count} deleted messages with pictures");
+}
@@ -1,70 +1,863 @@
-use std::collections::VecDeque;
-use std::{fs, mem};
-use std::future::Future;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::{fmt, fs, io, mem};
 use std::path::{Path, PathBuf};
-use std::pin::Pin;
 use std::str::FromStr;
-use futures::{FutureExt, stream, StreamExt};
-use image::ImageFormat;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use futures::{FutureExt, StreamExt};
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use std::io::Cursor;
+use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
 use image::imageops::FilterType;
 use once_cell::sync::Lazy;
-use reqwest::Url;
+use reqwest::{StatusCode, Url};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+
+use crate::image_metadata::{apply_orientation, CameraInfo, read_camera_info, read_exif, strip_metadata};
+use crate::near_duplicates::perceptual_hash;
+use crate::website::builder::gallery_page_info::{ThumbnailSet, ThumbnailVariant};
 
 static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
-    reqwest::Client::builder().build().unwrap()
+    reqwest::Client::builder().timeout(Duration::from_secs(30)).build().unwrap()
 });
 
+/// The smallest is based off of the column width in gallery-style.css .gallery{}, the rest are for HiDPI screens and
+/// phones showing a single column
+pub const DEFAULT_THUMBNAIL_WIDTHS: [u32; 3] = [250, 500, 1000];
+
+/// Full size originals are mirrored into this folder of the website root
+pub const ORIGINALS_FOLDER: &str = "originals";
+
+/// Thumbnails are saved into this folder of the website root, named by the content hash of their image
+pub const THUMBNAILS_FOLDER: &str = "thumbnails";
+
+/// Name of the thumbnail manifest file, kept in the website root
+pub const THUMBNAIL_MANIFEST_FILE_NAME: &str = ".thumbnail_manifest.json";
+
+/// Stands in for the content hash of attachments that can't be downloaded, naming the placeholder thumbnails shown for
+/// them
+const PLACEHOLDER_HASH: &str = "placeholder";
+/// How much of the SHA-256 of an image names its thumbnails, 128 bits is plenty to never collide
+const CONTENT_HASH_BYTES: usize = 16;
+
+/// How many images are fetched from discord at once
+const MAX_CONCURRENT_FETCHES: usize = 8;
+
+/// How many times an image is fetched before giving up on a transient failure
+const MAX_FETCH_ATTEMPTS: u32 = 4;
+/// Delay before the first retry, doubled for every retry after it
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Colour of the placeholder saved in place of thumbnails that can never be made
+const PLACEHOLDER_COLOR: Rgb<u8> = Rgb([200, 200, 200]);
+
+const JPEG_QUALITY: u8 = 85;
+#[cfg(feature = "webp")]
+const WEBP_QUALITY: f32 = 80.0;
+#[cfg(feature = "avif")]
+const AVIF_QUALITY: f32 = 70.0;
+/// From 1 (slowest, smallest files) to 10 (fastest)
+#[cfg(feature = "avif")]
+const AVIF_SPEED: u8 = 6;
+
+/// File format thumbnails are encoded in. Anything but JPEG also gets JPEG thumbnails for browsers that can't show it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThumbnailFormat {
+    Jpeg,
+    /// Needs the `webp` feature
+    Webp,
+    /// Needs the `avif` feature, encoding is a lot slower than the others
+    Avif,
+}
+
+impl ThumbnailFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "jpg",
+            ThumbnailFormat::Webp => "webp",
+            ThumbnailFormat::Avif => "avif",
+        }
+    }
+
+    fn mime_type(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "image/jpeg",
+            ThumbnailFormat::Webp => "image/webp",
+            ThumbnailFormat::Avif => "image/avif",
+        }
+    }
+
+    /// Returns an error if this build was compiled without the encoder for the format
+    pub fn check_available(self) -> Result<(), String> {
+        let (available, feature) = match self {
+            ThumbnailFormat::Jpeg => (true, ""),
+            ThumbnailFormat::Webp => (cfg!(feature = "webp"), "webp"),
+            ThumbnailFormat::Avif => (cfg!(feature = "avif"), "avif"),
+        };
+
+        if available {
+            Ok(())
+        } else {
+            Err(format!("Thumbnail format `{self:?}` needs this program to be built with the `{feature}` feature"))
+        }
+    }
+
+    /// The formats thumbnails are saved in, with the JPEG fallback last
+    fn with_fallback(self) -> Vec<ThumbnailFormat> {
+        if self == ThumbnailFormat::Jpeg {
+            vec![ThumbnailFormat::Jpeg]
+        } else {
+            vec![self, ThumbnailFormat::Jpeg]
+        }
+    }
+}
+
+/// A single thumbnail file to make from an image
+#[derive(Debug, Clone)]
+struct PlannedThumbnail {
+    format: ThumbnailFormat,
+    width: u32,
+    save_path: PathBuf,
+}
+
+#[derive(Debug)]
+pub enum DownloadError {
+    /// The request failed without a response, such as a timeout or a dropped connection
+    Request(reqwest::Error),
+    /// The attachment's url couldn't be parsed
+    InvalidUrl(Box<dyn Error + Send + Sync>),
+    /// The server responded with an error status
+    Status(StatusCode),
+    /// A local image (from fixtures or exports) couldn't be read
+    ReadFile(io::Error),
+    /// The downloaded bytes aren't an image we can decode
+    Decode(image::ImageError),
+    Encode(Box<dyn Error + Send + Sync>),
+    /// The original couldn't be parsed well enough to remove its metadata, so it isn't saved at all
+    StripMetadata(Box<dyn Error + Send + Sync>),
+    Save(Box<dyn Error + Send + Sync>),
+    /// Processing the image panicked, which a malformed file shouldn't be able to take the whole build down with
+    Process(tokio::task::JoinError),
+}
+
+impl DownloadError {
+    /// Returns true for failures that may not happen again if the download is retried
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DownloadError::Request(_) => true,
+            DownloadError::Status(status) => *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+            DownloadError::InvalidUrl(_) | DownloadError::ReadFile(_) | DownloadError::Decode(_) | DownloadError::Encode(_) | DownloadError::StripMetadata(_) | DownloadError::Save(_) | DownloadError::Process(_) => false,
+        }
+    }
+
+    /// Returns true if discord refused the url, which is what it does once a signed attachment url has expired. Fresh
+    /// urls can be had by fetching the attachment's message again.
+    pub fn is_refused_link(&self) -> bool {
+        matches!(self, DownloadError::Status(StatusCode::FORBIDDEN | StatusCode::NOT_FOUND))
+    }
+}
+
+impl Display for DownloadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::Request(err) => write!(f, "request failed: {err}"),
+            DownloadError::InvalidUrl(err) => write!(f, "invalid url: {err}"),
+            DownloadError::Status(status) => write!(f, "server responded with {status}"),
+            DownloadError::ReadFile(err) => write!(f, "failed to read local image: {err}"),
+            DownloadError::Decode(err) => write!(f, "failed to decode image: {err}"),
+            DownloadError::Encode(err) => write!(f, "failed to encode thumbnail: {err}"),
+            DownloadError::StripMetadata(err) => write!(f, "failed to strip metadata: {err}"),
+            DownloadError::Save(err) => write!(f, "failed to save: {err}"),
+            DownloadError::Process(err) => write!(f, "processing failed: {err}"),
+        }
+    }
+}
+
+impl Error for DownloadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DownloadError::Request(err) => Some(err),
+            DownloadError::InvalidUrl(err) => Some(err.as_ref()),
+            DownloadError::Status(_) => None,
+            DownloadError::ReadFile(err) => Some(err),
+            DownloadError::Decode(err) => Some(err),
+            DownloadError::Encode(err) => Some(err.as_ref()),
+            DownloadError::StripMetadata(err) => Some(err.as_ref()),
+            DownloadError::Save(err) => Some(err.as_ref()),
+            DownloadError::Process(err) => Some(err),
+        }
+    }
+}
+
+/// A queued download that failed, kept for the summary at the end of [`ThumbnailDownloader::download_all`]
+#[derive(Debug)]
+pub struct FailedDownload {
+    pub image_url: String,
+    pub attachment_id: String,
+    /// Whether the download was of the attachment's original rather than its thumbnails
+    pub original: bool,
+    /// Where the original would have been saved, none for thumbnails and originals with an invalid url
+    pub save_path: Option<PathBuf>,
+    pub error: DownloadError,
+    /// Whether the attachment was given placeholder thumbnails for good, which stops it being retried by later builds
+    pub placeholder_saved: bool,
+}
+
+/// What was learned about an image while making its thumbnails
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageInfo {
+    /// Size of the smallest thumbnail variant, which pages reserve space for before it loads
+    pub thumbnail_width: u32,
+    pub thumbnail_height: u32,
+    /// Average colour of the image as `#rrggbb`, shown in its place until it loads
+    pub placeholder_color: String,
+    pub camera_info: Option<CameraInfo>,
+    /// Size of the image once oriented, the size of its largest variant for the placeholder
+    pub source_width: u32,
+    pub source_height: u32,
+    /// [`perceptual_hash`] of the image, always worked out as thumbnails are only made once but only used for
+    /// collapsing near-duplicates if that's turned on. None for the placeholder, as failed downloads aren't
+    /// near-duplicates.
+    pub perceptual_hash: Option<u64>,
+}
+
+/// Which image every attachment turned out to be and what was learned about each image. Thumbnails are named by the
+/// content hash of their image, so reposted and re-uploaded images share thumbnails that are only made once.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ThumbnailManifest {
+    /// Content hash of every downloaded attachment, by attachment ID
+    attachments: BTreeMap<String, String>,
+    /// Info of every image, by content hash
+    images: BTreeMap<String, ImageInfo>,
+}
+
+impl ThumbnailManifest {
+    /// Loads the thumbnail manifest of a website, or an empty manifest if nothing was downloaded into it yet
+    pub fn load<P: AsRef<Path>>(website_root: P) -> Result<ThumbnailManifest, Box<dyn Error + Send + Sync>> {
+        let manifest_path = website_root.as_ref().join(THUMBNAIL_MANIFEST_FILE_NAME);
+        if !manifest_path.exists() {
+            return Ok(ThumbnailManifest::default());
+        }
+
+        let json = fs::read_to_string(&manifest_path)?;
+        let thumbnail_manifest = serde_json::from_str(&json)
+            .map_err(|err| format!("Failed to parse thumbnail manifest `{}`: {}", manifest_path.display(), err))?;
+
+        Ok(thumbnail_manifest)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, website_root: P) -> Result<(), Box<dyn Error + Send + Sync>> {
+        fs::create_dir_all(website_root.as_ref())?;
+        fs::write(website_root.as_ref().join(THUMBNAIL_MANIFEST_FILE_NAME), serde_json::to_string(self)?)?;
+
+        Ok(())
+    }
+
+    pub fn content_hash(&self, attachment_id: &str) -> Option<&str> {
+        self.attachments.get(attachment_id).map(String::as_str)
+    }
+
+    pub fn image_info(&self, attachment_id: &str) -> Option<&ImageInfo> {
+        self.images.get(self.content_hash(attachment_id)?)
+    }
+
+    /// Whether an attachment couldn't be downloaded and is shown with the placeholder thumbnails
+    pub fn is_placeholder(&self, attachment_id: &str) -> bool {
+        self.content_hash(attachment_id) == Some(PLACEHOLDER_HASH)
+    }
+}
+
+/// What a finished thumbnail download found out about its attachment
+#[derive(Debug)]
+struct DownloadedThumbnails {
+    attachment_id: String,
+    content_hash: String,
+    /// None when the image's thumbnails were already made, by an earlier build or another attachment of the same image
+    image_info: Option<ImageInfo>,
+}
+
+/// Resolves to what was found out for thumbnail downloads, and nothing for originals
+type QueuedDownload = BoxFuture<'static, Result<Option<DownloadedThumbnails>, FailedDownload>>;
+
+/// Limits on how much of each kind of work downloads do at once
+#[derive(Debug, Clone)]
+struct DownloadLimits {
+    fetches: Arc<Semaphore>,
+    /// Decoding, resizing and encoding, which runs on tokio's blocking pool so it doesn't stall the async runtime
+    processing: Arc<Semaphore>,
+}
+
+impl DownloadLimits {
+    /// Limits fetches to `max_concurrent_fetches` and processing to one image per core
+    fn new(max_concurrent_fetches: usize) -> DownloadLimits {
+        let core_count = thread::available_parallelism().map_or(1, |core_count| core_count.get());
+
+        DownloadLimits {
+            fetches: Arc::new(Semaphore::new(max_concurrent_fetches)),
+            processing: Arc::new(Semaphore::new(core_count)),
+        }
+    }
+
+    /// Fetches an image, then runs `process` on its bytes on the blocking pool
+    async fn fetch_then_process<T: Send + 'static>(
+        &self,
+        image_url: &Url,
+        process: impl FnOnce(Vec<u8>) -> Result<T, DownloadError> + Send + 'static,
+    ) -> Result<T, DownloadError> {
+        let fetch_permit = self.fetches.acquire().await.expect("Download limits are never closed");
+        let image_bytes = fetch_image_bytes_with_retries(image_url).await?;
+
+        // The fetch permit is held until processing starts, so fetched images can't pile up in memory waiting for a core
+        let processing_permit = self.processing.clone().acquire_owned().await.expect("Download limits are never closed");
+        drop(fetch_permit);
+
+        tokio::task::spawn_blocking(move || {
+            let _processing_permit = processing_permit;
+            process(image_bytes)
+        }).await.map_err(DownloadError::Process)?
+    }
+}
+
 pub struct ThumbnailDownloader {
-    queue: VecDeque<Pin<Box<dyn Future<Output=()>>>>,
+    queue: VecDeque<QueuedDownload>,
+    limits: DownloadLimits,
+    manifest: ThumbnailManifest,
+    /// Every attachment and original queued so far, so the same attachment posted twice is only downloaded once
+    queued_downloads: HashSet<String>,
+    /// Where the original of every attachment queued with [`ThumbnailDownloader::queue_original_download`] is saved,
+    /// with its url relative to the website root
+    originals: HashMap<String, (PathBuf, String)>,
+    /// Save paths of originals whose download failed this build
+    failed_originals: HashSet<PathBuf>,
+    /// Content hashes whose thumbnails are being made this build, so an image reposted under several attachments is
+    /// only processed once
+    claimed_content_hashes: Arc<Mutex<HashSet<String>>>,
+    /// Width of every image in the manifest when the downloader was made, which decides the thumbnail variants it has
+    known_source_widths: Arc<HashMap<String, u32>>,
+    /// Widths of the thumbnail variants made for every image, smallest first
+    thumbnail_widths: Vec<u32>,
+    thumbnail_format: ThumbnailFormat,
+    /// Keep the camera and exposure details in mirrored originals, all other metadata is always removed
+    keep_camera_info: bool,
+    /// Whether the urls being queued were just fetched, so refusing them means the attachment is gone for good
+    fresh_urls: bool,
+}
+
+impl Default for ThumbnailDownloader {
+    fn default() -> Self {
+        Self::new(DEFAULT_THUMBNAIL_WIDTHS.to_vec(), ThumbnailFormat::Jpeg, false, ThumbnailManifest::default())
+    }
 }
 
 impl ThumbnailDownloader {
-    pub fn new() -> ThumbnailDownloader {
+    /// Creates a downloader making a thumbnail of each of `thumbnail_widths` for every image, reusing the thumbnails of
+    /// images already in `manifest`
+    pub fn new(mut thumbnail_widths: Vec<u32>, thumbnail_format: ThumbnailFormat, keep_camera_info: bool, manifest: ThumbnailManifest) -> ThumbnailDownloader {
+        thumbnail_widths.sort_unstable();
+        thumbnail_widths.dedup();
+        assert!(!thumbnail_widths.is_empty(), "At least one thumbnail width is needed");
+        let known_source_widths = manifest.images.iter().map(|(content_hash, image_info)| (content_hash.clone(), image_info.source_width)).collect();
+
         ThumbnailDownloader {
             queue: Default::default(),
+            limits: DownloadLimits::new(MAX_CONCURRENT_FETCHES),
+            manifest,
+            queued_downloads: Default::default(),
+            originals: Default::default(),
+            failed_originals: Default::default(),
+            claimed_content_hashes: Default::default(),
+            known_source_widths: Arc::new(known_source_widths),
+            thumbnail_widths,
+            thumbnail_format,
+            keep_camera_info,
+            fresh_urls: false,
         }
     }
 
-    pub fn queue_download<P: AsRef<Path>>(&mut self, website_root: P, image_url: &str) -> String {
-        let image_url = Url::from_str(image_url).unwrap();
+    pub fn manifest(&self) -> &ThumbnailManifest {
+        &self.manifest
+    }
 
-        let url_path = image_url.path().trim_start_matches("/");
+    /// Marks the urls queued from now on as just fetched, see [`DownloadError::is_refused_link`]
+    pub fn set_fresh_urls(&mut self) {
+        self.fresh_urls = true;
+    }
 
-        let save_path = website_root.as_ref().to_path_buf().join(url_path);
+    /// Whether the manifest knows the image of an attachment and its thumbnails are all saved
+    pub fn thumbnails_saved<P: AsRef<Path>>(&self, website_root: P, attachment_id: &str) -> bool {
+        self.manifest.content_hash(attachment_id).is_some_and(|content_hash| {
+            let source_width = self.manifest.images.get(content_hash).map(|image_info| image_info.source_width);
+            let saved_widths = saved_thumbnail_widths(&self.thumbnail_widths, source_width);
+            all_thumbnails_saved(&planned_thumbnails(website_root.as_ref(), content_hash, &saved_widths, &self.thumbnail_format.with_fallback()))
+        })
+    }
 
-        if !save_path.exists() {
-            let save_path = save_path.clone();
-            self.queue.push_back(async move {
-                println!("Starting download: {:?}", save_path);
-                let thumbnail_image = async {
-                    let response = CLIENT.get(image_url).send().await.unwrap(); // This can fail if we can't connect to the discord CDN, if we can't connect there isn't much reason continuing anyway
-                    let image_bytes = response.bytes().await.unwrap(); // I think we can just unwrap here, I *think* that the only was this panics is if we run out of memory or something else where the best option is to just panic
-                    let image = image::load_from_memory(&image_bytes).unwrap();
+    /// Whether the original at `image_url` is already mirrored into the website
+    pub fn original_saved<P: AsRef<Path>>(&self, website_root: P, image_url: &str) -> bool {
+        Url::from_str(image_url).is_ok_and(|image_url| website_root.as_ref().join(ORIGINALS_FOLDER).join(url_save_path(&image_url)).exists())
+    }
+
+    /// Queues the thumbnails of an attachment, unless the manifest knows its image and its thumbnails are all saved
+    pub fn queue_download<P: AsRef<Path>>(&mut self, website_root: P, attachment_id: &str, image_url: &str) {
+        if self.thumbnails_saved(&website_root, attachment_id) {
+            println!("Skipping already saved thumbnails of attachment {attachment_id}");
+            return;
+        }
+        let website_root = website_root.as_ref().to_path_buf();
+        let formats = self.thumbnail_format.with_fallback();
+        if !self.queued_downloads.insert(attachment_id.to_owned()) {
+            return;
+        }
+
+        let image_url = match Url::from_str(image_url) {
+            Ok(image_url) => image_url,
+            Err(err) => {
+                eprintln!("Error downloading thumbnails of attachment {attachment_id}: invalid url `{image_url}`: {err}");
+                let failed_download = FailedDownload {
+                    image_url: image_url.to_owned(),
+                    attachment_id: attachment_id.to_owned(),
+                    original: false,
+                    save_path: None,
+                    error: DownloadError::InvalidUrl(err.into()),
+                    placeholder_saved: true,
+                };
+                self.queue.push_back(async move { Err(failed_download) }.boxed());
+                return;
+            }
+        };
+        let attachment_id = attachment_id.to_owned();
+        let thumbnail_widths = self.thumbnail_widths.clone();
+        let claimed_content_hashes = self.claimed_content_hashes.clone();
+        let known_source_widths = self.known_source_widths.clone();
+        let limits = self.limits.clone();
+        let fresh_url = self.fresh_urls;
+        self.queue.push_back(async move {
+            let processed_attachment_id = attachment_id.clone();
+            let download_result = limits.fetch_then_process(&image_url, move |image_bytes| {
+                let content_hash = content_hash(&image_bytes);
+                let saved_widths = saved_thumbnail_widths(&thumbnail_widths, known_source_widths.get(&content_hash).copied());
+                let thumbnails_saved = all_thumbnails_saved(&planned_thumbnails(&website_root, &content_hash, &saved_widths, &formats));
+                let image_info = if thumbnails_saved || !claimed_content_hashes.lock().unwrap().insert(content_hash.clone()) {
+                    println!("Reusing thumbnails of image {content_hash} for attachment {processed_attachment_id}");
+                    None
+                } else {
+                    println!("Making thumbnails of image {content_hash} for attachment {processed_attachment_id}");
+                    Some(make_thumbnails(&image_bytes, &website_root, &content_hash, &thumbnail_widths, &formats)?)
+                };
+
+                Ok(DownloadedThumbnails {
+                    attachment_id: processed_attachment_id,
+                    content_hash,
+                    image_info,
+                })
+            }).await;
 
-                    image.resize(250, 250, FilterType::Triangle) // This size here is based off of the values in gallery-style.css .gallery{}
-                }.await;
+            match download_result {
+                Ok(downloaded_thumbnails) => {
+                    println!("Successfully saved thumbnails of attachment {attachment_id}");
+                    Ok(Some(downloaded_thumbnails))
+                }
+                Err(error) => {
+                    eprintln!("Error downloading thumbnails of attachment {attachment_id}: {error}");
+                    // Transient failures and refused links that may just have expired are left to retry, anything else will
+                    // never work
+                    let placeholder_saved = !error.is_transient() && (fresh_url || !error.is_refused_link());
 
-                fs::create_dir_all(save_path.parent().unwrap()).unwrap();
+                    Err(FailedDownload {
+                        image_url: image_url.to_string(),
+                        attachment_id,
+                        original: false,
+                        save_path: None,
+                        error,
+                        placeholder_saved,
+                    })
+                }
+            }
+        }.boxed())
+    }
+
+    /// Queues a copy of an attachment's full size image with its identifying metadata removed, which
+    /// [`ThumbnailDownloader::original_url`] gives the url of once it's saved
+    pub fn queue_original_download<P: AsRef<Path>>(&mut self, website_root: P, attachment_id: &str, image_url: &str) {
+        let image_url = match Url::from_str(image_url) {
+            Ok(image_url) => image_url,
+            Err(err) => {
+                eprintln!("Error downloading original of attachment {attachment_id}: invalid url `{image_url}`: {err}");
+                let failed_download = FailedDownload {
+                    image_url: image_url.to_owned(),
+                    attachment_id: attachment_id.to_owned(),
+                    original: true,
+                    save_path: None,
+                    error: DownloadError::InvalidUrl(err.into()),
+                    placeholder_saved: false,
+                };
+                self.queue.push_back(async move { Err(failed_download) }.boxed());
+                return;
+            }
+        };
+        let save_path = website_root.as_ref().join(ORIGINALS_FOLDER).join(url_save_path(&image_url));
+        let original_url = save_path.strip_prefix(&website_root).unwrap().to_string_lossy().to_string();
+        self.originals.insert(attachment_id.to_owned(), (save_path.clone(), original_url));
+        self.failed_originals.remove(&save_path);
 
-                match thumbnail_image.save_with_format(&save_path, ImageFormat::Jpeg) {
-                    Ok(_) => {
-                        println!("Successfully saved thumbnail `{}`", save_path.display())
+        if !save_path.exists() && self.queued_downloads.insert(save_path.to_string_lossy().to_string()) {
+            let save_path = save_path.clone();
+            let attachment_id = attachment_id.to_owned();
+            let keep_camera_info = self.keep_camera_info;
+            let limits = self.limits.clone();
+            self.queue.push_back(async move {
+                let processed_save_path = save_path.clone();
+                let result = limits.fetch_then_process(&image_url, move |image_bytes| {
+                    println!("Saving original: {:?}", processed_save_path);
+                    let image_bytes = strip_metadata(&image_bytes, keep_camera_info).map_err(DownloadError::StripMetadata)?;
+                    create_parent_dir(&processed_save_path)?;
+                    fs::write(&processed_save_path, image_bytes).map_err(|err| DownloadError::Save(err.into()))
+                }).await;
+
+                match result {
+                    Ok(()) => {
+                        println!("Successfully saved original `{}`", save_path.display());
+                        Ok(None)
                     }
-                    Err(err) => {
-                        eprintln!("Error saving thumbnail `{}`: {}", save_path.display(), err)
+                    Err(error) => {
+                        eprintln!("Error downloading original `{}`: {}", save_path.display(), error);
+                        Err(FailedDownload {
+                            image_url: image_url.to_string(),
+                            attachment_id,
+                            original: true,
+                            save_path: Some(save_path),
+                            error,
+                            placeholder_saved: false,
+                        })
                     }
-                };
+                }
             }.boxed())
         } else {
-            println!("Skipping already saved thumbnail `{}`", save_path.display())
+            println!("Skipping already saved original `{}`", save_path.display())
         }
+    }
 
-        save_path.strip_prefix(website_root).unwrap().to_string_lossy().to_string()
+    /// Url of an attachment's mirrored original relative to the website root, none if it wasn't mirrored or couldn't be
+    /// saved. Only known once [`ThumbnailDownloader::download_all`] has run.
+    pub fn original_url(&self, attachment_id: &str) -> Option<&str> {
+        let (save_path, original_url) = self.originals.get(attachment_id)?;
+        if self.failed_originals.contains(save_path) {
+            return None;
+        }
+
+        Some(original_url)
     }
 
-    pub async fn download_all(mut self) {
+    /// Runs every queued download and records the results in the manifest, printing a summary of the ones that failed
+    pub async fn download_all<P: AsRef<Path>>(&mut self, website_root: P) -> Vec<FailedDownload> {
         let queue = mem::take(&mut self.queue);
-        let download_queue = stream::iter(queue.into_iter()).buffer_unordered(5).collect::<Vec<_>>();
-        download_queue.await;
+        let download_count = queue.len();
+        // Every download is started at once, the download limits decide how many actually fetch and process at a time
+        let download_results = queue.into_iter().collect::<FuturesUnordered<_>>().collect::<Vec<_>>().await;
+
+        let mut failed_downloads = Vec::new();
+        for download_result in download_results {
+            match download_result {
+                Ok(Some(DownloadedThumbnails { attachment_id, content_hash, image_info })) => {
+                    if let Some(image_info) = image_info {
+                        self.manifest.images.insert(content_hash.clone(), image_info);
+                    }
+                    self.manifest.attachments.insert(attachment_id, content_hash);
+                }
+                Ok(None) => {}
+                Err(failed_download) => failed_downloads.push(failed_download),
+            }
+        }
+
+        // Attachments without thumbnails are shown with placeholders, including ones left for the next build to retry
+        let failed_thumbnails = failed_downloads.iter().any(|failed_download| !failed_download.original);
+        let placeholders_saved = failed_thumbnails && match self.save_placeholders(website_root.as_ref()) {
+            Ok(()) => true,
+            Err(err) => {
+                eprintln!("Error saving placeholder thumbnails: {err}");
+                false
+            }
+        };
+        for failed_download in &mut failed_downloads {
+            // Failed downloads may be queued again, such as with a fresh url
+            match (failed_download.original, &failed_download.save_path) {
+                (true, Some(save_path)) => {
+                    self.queued_downloads.remove(&save_path.to_string_lossy().to_string());
+                    self.failed_originals.insert(save_path.clone());
+                }
+                (true, None) => {}
+                (false, _) => {
+                    self.queued_downloads.remove(&failed_download.attachment_id);
+                }
+            }
+            failed_download.placeholder_saved &= placeholders_saved;
+            if failed_download.placeholder_saved {
+                self.manifest.attachments.insert(failed_download.attachment_id.clone(), PLACEHOLDER_HASH.to_owned());
+            }
+        }
+
+        if !failed_downloads.is_empty() {
+            eprintln!("{} of {} downloads failed:", failed_downloads.len(), download_count);
+            for failed_download in &failed_downloads {
+                let outcome = if failed_download.placeholder_saved {
+                    "placeholder saved"
+                } else if failed_download.error.is_transient() {
+                    "will retry next build"
+                } else if failed_download.error.is_refused_link() {
+                    "link refused, will retry with a fresh link"
+                } else {
+                    "skipped"
+                };
+                let download_name = match (failed_download.original, &failed_download.save_path) {
+                    (true, Some(save_path)) => format!("`{}`", save_path.display()),
+                    (true, None) => format!("Original of attachment {}", failed_download.attachment_id),
+                    (false, _) => format!("Thumbnails of attachment {}", failed_download.attachment_id),
+                };
+                eprintln!("  {download_name} from {}: {} ({outcome})", failed_download.image_url, failed_download.error);
+            }
+        }
+
+        failed_downloads
+    }
+
+    /// Returns the thumbnails of an attachment per format with the JPEG fallback last, which are placeholders if it
+    /// couldn't be downloaded. Variants are ordered smallest first, with their paths relative to the website root.
+    pub fn thumbnail_sets(&self, attachment_id: &str) -> Vec<ThumbnailSet> {
+        let content_hash = self.manifest.content_hash(attachment_id).unwrap_or(PLACEHOLDER_HASH);
+        let source_width = self.manifest.images.get(content_hash).map(|image_info| image_info.source_width);
+        let saved_widths = saved_thumbnail_widths(&self.thumbnail_widths, source_width);
+
+        self.thumbnail_format
+            .with_fallback()
+            .into_iter()
+            .map(|format| ThumbnailSet {
+                mime_type: format.mime_type().to_owned(),
+                variants: saved_widths.iter()
+                    .map(|&width| ThumbnailVariant {
+                        url: variant_path(content_hash, width, format).to_string_lossy().to_string(),
+                        // Variants are named by the width they were made for, but small images aren't upscaled to it
+                        width: source_width.map_or(width, |source_width| width.min(source_width)),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Saves the placeholder thumbnails shown for attachments that couldn't be downloaded, if they aren't already
+    fn save_placeholders(&mut self, website_root: &Path) -> Result<(), DownloadError> {
+        let formats = self.thumbnail_format.with_fallback();
+        let planned_placeholders = planned_thumbnails(website_root, PLACEHOLDER_HASH, &self.thumbnail_widths, &formats);
+        for planned_placeholder in planned_placeholders.iter().filter(|planned_placeholder| !planned_placeholder.save_path.exists()) {
+            save_placeholder(planned_placeholder)?;
+        }
+
+        let [r, g, b] = PLACEHOLDER_COLOR.0;
+        let largest_width = self.thumbnail_widths[self.thumbnail_widths.len() - 1];
+        self.manifest.images.insert(PLACEHOLDER_HASH.to_owned(), ImageInfo {
+            thumbnail_width: self.thumbnail_widths[0],
+            thumbnail_height: self.thumbnail_widths[0],
+            placeholder_color: format!("#{r:02x}{g:02x}{b:02x}"),
+            camera_info: None,
+            source_width: largest_width,
+            source_height: largest_width,
+            perceptual_hash: None,
+        });
+
+        Ok(())
+    }
+}
+
+/// Where an image is stored relative to the website root, which mirrors the url path of the image
+fn url_save_path(image_url: &Url) -> PathBuf {
+    if image_url.scheme() == "file" {
+        // Local images (from fixtures) don't have a meaningful url path, so keep them all in one folder
+        let file_name = image_url.path_segments().and_then(|mut segments| segments.next_back()).unwrap_or_default();
+        Path::new("local").join(file_name)
+    } else {
+        PathBuf::from(image_url.path().trim_start_matches('/'))
+    }
+}
+
+/// Hashes the bytes of a source image into the name its thumbnails are saved under
+fn content_hash(image_bytes: &[u8]) -> String {
+    Sha256::digest(image_bytes)[..CONTENT_HASH_BYTES].iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Where a thumbnail variant of an image is stored relative to the website root
+fn variant_path(content_hash: &str, width: u32, format: ThumbnailFormat) -> PathBuf {
+    Path::new(THUMBNAILS_FOLDER).join(format!("{content_hash}_{width}w.{}", format.extension()))
+}
+
+fn planned_thumbnails(website_root: &Path, content_hash: &str, thumbnail_widths: &[u32], formats: &[ThumbnailFormat]) -> Vec<PlannedThumbnail> {
+    formats.iter()
+        .flat_map(|&format| thumbnail_widths.iter().map(move |&width| (format, width)))
+        .map(|(format, width)| PlannedThumbnail {
+            format,
+            width,
+            save_path: website_root.join(variant_path(content_hash, width, format)),
+        })
+        .collect()
+}
+
+/// Widths of the variants saved of an image `source_width` wide, all of them if the image isn't known yet. Images are
+/// never upscaled, so only the first variant at least as wide as the image is saved, as the ones after it would be the
+/// same.
+fn saved_thumbnail_widths(thumbnail_widths: &[u32], source_width: Option<u32>) -> Vec<u32> {
+    let Some(source_width) = source_width else {
+        return thumbnail_widths.to_vec();
+    };
+    let saved_count = thumbnail_widths.iter().position(|&width| width >= source_width).map_or(thumbnail_widths.len(), |index| index + 1);
+
+    thumbnail_widths[..saved_count].to_vec()
+}
+
+fn all_thumbnails_saved(planned_thumbnails: &[PlannedThumbnail]) -> bool {
+    planned_thumbnails.iter().all(|planned_thumbnail| planned_thumbnail.save_path.exists())
+}
+
+/// Decodes a downloaded image and saves every thumbnail variant it gets, returning what was learned about it. This is
+/// CPU heavy, so it's run on the blocking pool.
+fn make_thumbnails(image_bytes: &[u8], website_root: &Path, content_hash: &str, thumbnail_widths: &[u32], formats: &[ThumbnailFormat]) -> Result<ImageInfo, DownloadError> {
+    let image = image::load_from_memory(image_bytes).map_err(DownloadError::Decode)?;
+    let exif = read_exif(image_bytes);
+    let image = apply_orientation(image, exif.as_ref());
+    let planned_thumbnails = planned_thumbnails(website_root, content_hash, &saved_thumbnail_widths(thumbnail_widths, Some(image.width())), formats);
+
+    // Every format has a variant of each width, so each width is only resized once
+    let mut resized_images: HashMap<u32, DynamicImage> = HashMap::new();
+    for planned_thumbnail in &planned_thumbnails {
+        let thumbnail_image = resized_images.entry(planned_thumbnail.width).or_insert_with(|| {
+            // Never upscale, the largest variant of a small image is the size of the original
+            if image.width() > planned_thumbnail.width {
+                image.resize(planned_thumbnail.width, u32::MAX, FilterType::Triangle)
+            } else {
+                image.clone()
+            }
+        });
+
+        save_thumbnail(thumbnail_image, planned_thumbnail)?;
+    }
+
+    let (source_width, source_height) = (image.width(), image.height());
+    let smallest_thumbnail = resized_images.into_iter().min_by_key(|(width, _)| *width).map_or(image, |(_, thumbnail_image)| thumbnail_image);
+    Ok(ImageInfo {
+        thumbnail_width: smallest_thumbnail.width(),
+        thumbnail_height: smallest_thumbnail.height(),
+        placeholder_color: average_color(&smallest_thumbnail),
+        camera_info: exif.as_ref().and_then(read_camera_info),
+        source_width,
+        source_height,
+        perceptual_hash: Some(perceptual_hash(&smallest_thumbnail)),
+    })
+}
+
+/// Averages every pixel of an image into a `#rrggbb` colour
+fn average_color(image: &DynamicImage) -> String {
+    let rgb_image = image.to_rgb8();
+    let pixel_count = u64::from(rgb_image.width()) * u64::from(rgb_image.height());
+    let mut channel_totals = [0u64; 3];
+    for pixel in rgb_image.pixels() {
+        for (channel_total, channel) in channel_totals.iter_mut().zip(pixel.0) {
+            *channel_total += u64::from(channel);
+        }
+    }
+
+    let [r, g, b] = channel_totals.map(|channel_total| channel_total / pixel_count.max(1));
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// Saves a plain square where a thumbnail couldn't be made, so the gallery doesn't show a broken image
+fn save_placeholder(planned_thumbnail: &PlannedThumbnail) -> Result<(), DownloadError> {
+    let width = planned_thumbnail.width;
+    let placeholder_image = DynamicImage::ImageRgb8(RgbImage::from_pixel(width, width, PLACEHOLDER_COLOR));
+
+    save_thumbnail(&placeholder_image, planned_thumbnail)
+}
+
+fn save_thumbnail(thumbnail_image: &DynamicImage, planned_thumbnail: &PlannedThumbnail) -> Result<(), DownloadError> {
+    let thumbnail_bytes = encode_thumbnail(thumbnail_image, planned_thumbnail.format)?;
+
+    create_parent_dir(&planned_thumbnail.save_path)?;
+    // Replace rather than write through an existing thumbnail, which is hard linked into the live website while building
+    if planned_thumbnail.save_path.exists() {
+        fs::remove_file(&planned_thumbnail.save_path).map_err(|err| DownloadError::Save(err.into()))?;
+    }
+    fs::write(&planned_thumbnail.save_path, thumbnail_bytes).map_err(|err| DownloadError::Save(err.into()))
+}
+
+fn encode_thumbnail(thumbnail_image: &DynamicImage, format: ThumbnailFormat) -> Result<Vec<u8>, DownloadError> {
+    match format {
+        ThumbnailFormat::Jpeg => {
+            // JPEG has no alpha channel
+            let rgb_image = DynamicImage::ImageRgb8(thumbnail_image.to_rgb8());
+            let mut thumbnail_bytes = Cursor::new(Vec::new());
+            rgb_image.write_to(&mut thumbnail_bytes, ImageOutputFormat::Jpeg(JPEG_QUALITY)).map_err(|err| DownloadError::Encode(err.into()))?;
+
+            Ok(thumbnail_bytes.into_inner())
+        }
+        #[cfg(feature = "webp")]
+        ThumbnailFormat::Webp => {
+            let rgba_image = thumbnail_image.to_rgba8();
+            let thumbnail_bytes = webp::Encoder::from_rgba(&rgba_image, rgba_image.width(), rgba_image.height()).encode(WEBP_QUALITY);
+
+            Ok(thumbnail_bytes.to_vec())
+        }
+        #[cfg(feature = "avif")]
+        ThumbnailFormat::Avif => {
+            let rgba_image = thumbnail_image.to_rgba8();
+            let pixels = rgba_image.pixels().map(|pixel| ravif::RGBA8::new(pixel[0], pixel[1], pixel[2], pixel[3])).collect::<Vec<_>>();
+            let encoded_image = ravif::Encoder::new()
+                .with_quality(AVIF_QUALITY)
+                .with_speed(AVIF_SPEED)
+                .encode_rgba(ravif::Img::new(&pixels, rgba_image.width() as usize, rgba_image.height() as usize))
+                .map_err(|err| DownloadError::Encode(err.into()))?;
+
+            Ok(encoded_image.avif_file)
+        }
+        #[allow(unreachable_patterns)]
+        format => Err(DownloadError::Encode(format.check_available().unwrap_err().into())),
+    }
+}
+
+fn create_parent_dir(save_path: &Path) -> Result<(), DownloadError> {
+    fs::create_dir_all(save_path.parent().unwrap()).map_err(|err| DownloadError::Save(err.into()))
+}
+
+/// Fetches an image, retrying transient failures with exponential backoff
+async fn fetch_image_bytes_with_retries(image_url: &Url) -> Result<Vec<u8>, DownloadError> {
+    let mut retry_delay = INITIAL_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match fetch_image_bytes(image_url).await {
+            Err(err) if err.is_transient() && attempt < MAX_FETCH_ATTEMPTS => {
+                eprintln!("Attempt {attempt} to fetch {image_url} failed, retrying in {retry_delay:?}: {err}");
+                tokio::time::sleep(retry_delay).await;
+                retry_delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn fetch_image_bytes(image_url: &Url) -> Result<Vec<u8>, DownloadError> {
+    if image_url.scheme() == "file" {
+        let image_path = image_url.to_file_path().map_err(|()| DownloadError::InvalidUrl(format!("`{image_url}` isn't a local path").into()))?;
+        fs::read(image_path).map_err(DownloadError::ReadFile)
+    } else {
+        let response = CLIENT.get(image_url.clone()).send().await.map_err(DownloadError::Request)?;
+        if !response.status().is_success() {
+            return Err(DownloadError::Status(response.status()));
+        }
+
+        let image_bytes = response.bytes().await.map_err(DownloadError::Request)?;
+        Ok(image_bytes.to_vec())
     }
 }
@@ -0,0 +1,292 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::Instant;
+use twilight_model::id::Id;
+use twilight_model::id::marker::{ChannelMarker, MessageMarker};
+
+use crate::commands::{GalleryCommand, GalleryCommandRequest, register_gallery_command};
+use crate::gallery_build::{GalleryBuildTarget, sync_gallery_channels, write_gallery_website};
+use crate::source::{BasicMessageInfo, SourceResult};
+use crate::source::twilight::TwilightSource;
+use crate::sync_state::SyncState;
+
+/// How long a failed build waits before it's tried again, unless new posts trigger a rebuild before then
+const BUILD_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// A message event or gallery command from the gateway that may change a watched gallery
+#[derive(Debug)]
+pub enum WatchEvent {
+    MessageCreated {
+        channel_id: Id<ChannelMarker>,
+        message: BasicMessageInfo,
+    },
+    /// Gateway updates only hold the changed fields, so the whole message has to be fetched again
+    MessageUpdated {
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+    },
+    MessagesDeleted {
+        channel_id: Id<ChannelMarker>,
+        message_ids: Vec<Id<MessageMarker>>,
+    },
+    GalleryCommand(GalleryCommandRequest),
+}
+
+impl WatchEvent {
+    /// Returns the channel a message event happened in
+    fn channel_id(&self) -> Option<Id<ChannelMarker>> {
+        match self {
+            WatchEvent::MessageCreated { channel_id, .. }
+            | WatchEvent::MessageUpdated { channel_id, .. }
+            | WatchEvent::MessagesDeleted { channel_id, .. } => Some(*channel_id),
+            WatchEvent::GalleryCommand(_) => None,
+        }
+    }
+}
+
+struct WatchedGallery {
+    build_target: GalleryBuildTarget,
+    sync_state: SyncState,
+    /// When the website should next be rewritten, pushed back by every change so bursts of posts cause a single rebuild
+    rebuild_at: Option<Instant>,
+    last_built_at: Option<DateTime<Utc>>,
+    last_build_error: Option<String>,
+    /// Whether the messages of the gallery channels were fetched, which a failed first build may not have got to
+    synced: bool,
+}
+
+impl WatchedGallery {
+    fn watches_channel(&self, channel_id: Id<ChannelMarker>) -> bool {
+        self.build_target.gallery_channels().iter().any(|channel| channel.id == channel_id)
+    }
+
+    fn website_name(&self) -> String {
+        self.build_target.build_options.website_root.display().to_string()
+    }
+
+    /// Writes the website from the synced messages, remembering how it went for `/gallery status`. Failed builds are
+    /// retried after [`BUILD_RETRY_DELAY`].
+    async fn rebuild(&mut self, source: &TwilightSource) -> SourceResult<()> {
+        self.rebuild_at = None;
+
+        println!("Rebuilding gallery `{}`", self.website_name());
+        let result = self.sync_and_write(source).await;
+        match &result {
+            Ok(()) => {
+                self.last_built_at = Some(Utc::now());
+                self.last_build_error = None;
+            }
+            Err(err) => {
+                eprintln!("Error rebuilding gallery `{}`, retrying in {} minutes: {err}", self.website_name(), BUILD_RETRY_DELAY.as_secs() / 60);
+                self.last_build_error = Some(err.to_string());
+                self.rebuild_at = Some(Instant::now() + BUILD_RETRY_DELAY);
+            }
+        }
+
+        result
+    }
+
+    /// Fetches the messages of the gallery channels if that hasn't worked yet, then writes the website
+    async fn sync_and_write(&mut self, source: &TwilightSource) -> SourceResult<()> {
+        if !self.synced {
+            self.sync_state = sync_gallery_channels(source, &self.build_target).await?;
+            self.synced = true;
+        }
+
+        write_gallery_website(source, &mut self.sync_state, &self.build_target).await
+    }
+
+    /// Refetches the whole history of every gallery channel, picking up anything missed while disconnected
+    async fn resync(&mut self, source: &TwilightSource) -> SourceResult<()> {
+        let history_limit = &self.build_target.build_options.history_limit;
+        for channel in self.build_target.gallery_channels() {
+            self.sync_state.sync_channel(source, channel.id, history_limit, true).await?;
+        }
+        self.synced = true;
+
+        Ok(())
+    }
+
+    fn status(&self) -> String {
+        let gallery_channels = self.build_target.gallery_channels();
+        let picture_count = gallery_channels.iter()
+            .flat_map(|channel| self.sync_state.picture_messages(channel.id))
+            .flat_map(|message| &message.attachments)
+            .filter(|attachment| attachment.is_image())
+            .count();
+
+        let mut status = format!(
+            "`{}` ({}): {} channels with {picture_count} pictures",
+            self.website_name(),
+            self.build_target.category.name.as_deref().unwrap_or("No Category Name"),
+            gallery_channels.len(),
+        );
+        match self.last_built_at {
+            // Discord shows these timestamps in the reader's timezone
+            Some(last_built_at) => status += &format!(", last built <t:{}:R>", last_built_at.timestamp()),
+            None => status += ", not built yet",
+        }
+        if self.rebuild_at.is_some() {
+            status += ", rebuild pending";
+        }
+        if let Some(last_build_error) = &self.last_build_error {
+            status += &format!("\nLast build failed: {last_build_error}");
+        }
+
+        status
+    }
+}
+
+/// Builds every target once, then keeps them up to date with the message events from the gateway until it disconnects.
+/// The `/gallery` command is registered in every guild being built from, and is answered here too.
+///
+/// Channels created or moved into a watched category after startup are not picked up until the next restart.
+pub async fn watch_galleries(
+    source: &TwilightSource,
+    mut watch_events: UnboundedReceiver<WatchEvent>,
+    build_targets: Vec<GalleryBuildTarget>,
+    debounce: Duration,
+) -> SourceResult<()> {
+    let mut guild_ids = build_targets.iter().map(|build_target| build_target.guild.id).collect::<Vec<_>>();
+    guild_ids.sort_unstable();
+    guild_ids.dedup();
+    for guild_id in guild_ids {
+        // The bot may have been invited without the scope for commands, which shouldn't stop it from watching
+        if let Err(err) = register_gallery_command(source.http(), source.application_id(), guild_id).await {
+            eprintln!("Error registering the gallery command in guild `{guild_id}`: {err}");
+        }
+    }
+
+    let mut watched_galleries = Vec::new();
+    for build_target in build_targets {
+        let mut watched_gallery = WatchedGallery {
+            build_target,
+            sync_state: SyncState::default(),
+            rebuild_at: None,
+            last_built_at: None,
+            last_build_error: None,
+            synced: false,
+        };
+        // A failed first build is retried like any other, so it doesn't stop the galleries from being watched
+        let _ = watched_gallery.rebuild(source).await;
+
+        watched_galleries.push(watched_gallery);
+    }
+    println!("Watching {} galleries for new photos", watched_galleries.len());
+
+    loop {
+        let next_rebuild_at = watched_galleries.iter().filter_map(|watched_gallery| watched_gallery.rebuild_at).min();
+
+        tokio::select! {
+            watch_event = watch_events.recv() => {
+                let Some(watch_event) = watch_event else {
+                    return Err("Gateway connection closed".into());
+                };
+
+                if let WatchEvent::GalleryCommand(command_request) = watch_event {
+                    run_gallery_command(source, &mut watched_galleries, command_request).await;
+                    continue;
+                }
+
+                let Some(channel_id) = watch_event.channel_id() else {
+                    continue;
+                };
+                if !watched_galleries.iter().any(|watched_gallery| watched_gallery.watches_channel(channel_id)) {
+                    continue;
+                }
+
+                if let Err(err) = apply_watch_event(source, &mut watched_galleries, watch_event, debounce).await {
+                    eprintln!("Error applying message event in channel `{channel_id}`: {err}");
+                }
+            }
+
+            _ = tokio::time::sleep_until(next_rebuild_at.unwrap_or_else(Instant::now)), if next_rebuild_at.is_some() => {
+                let now = Instant::now();
+                for watched_gallery in watched_galleries.iter_mut().filter(|watched_gallery| watched_gallery.rebuild_at.is_some_and(|rebuild_at| rebuild_at <= now)) {
+                    // Errors are reported by the rebuild and kept for `/gallery status`
+                    let _ = watched_gallery.rebuild(source).await;
+                }
+            }
+        }
+    }
+}
+
+async fn apply_watch_event(source: &TwilightSource, watched_galleries: &mut [WatchedGallery], watch_event: WatchEvent, debounce: Duration) -> SourceResult<()> {
+    let Some(channel_id) = watch_event.channel_id() else {
+        return Ok(());
+    };
+    let message = match &watch_event {
+        WatchEvent::MessageCreated { message, .. } => Some(message.clone()),
+        WatchEvent::MessageUpdated { message_id, .. } => Some(source.message(channel_id, *message_id).await?),
+        WatchEvent::MessagesDeleted { .. } | WatchEvent::GalleryCommand(_) => None,
+    };
+
+    for watched_gallery in watched_galleries.iter_mut().filter(|watched_gallery| watched_gallery.watches_channel(channel_id)) {
+        let changed = match (&watch_event, &message) {
+            (WatchEvent::MessagesDeleted { message_ids, .. }, _) => {
+                // Every message has to be removed, so don't stop at the first one with pictures
+                let removed_count = message_ids.iter().filter(|message_id| watched_gallery.sync_state.remove_message(channel_id, **message_id)).count();
+                removed_count > 0
+            }
+            (_, Some(message)) => watched_gallery.sync_state.upsert_message(channel_id, message.clone()),
+            (_, None) => false,
+        };
+
+        if changed {
+            watched_gallery.rebuild_at = Some(Instant::now() + debounce);
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs a gallery command on every watched gallery of the guild it was used in, replying with the outcome
+async fn run_gallery_command(source: &TwilightSource, watched_galleries: &mut [WatchedGallery], command_request: GalleryCommandRequest) {
+    let mut guild_galleries = watched_galleries.iter_mut()
+        .filter(|watched_gallery| watched_gallery.build_target.guild.id == command_request.guild_id)
+        .collect::<Vec<_>>();
+
+    let reply = if guild_galleries.is_empty() {
+        "No galleries are being built from this server".to_owned()
+    } else {
+        match command_request.command {
+            GalleryCommand::Rebuild => rebuild_galleries(source, &mut guild_galleries, &command_request).await,
+            GalleryCommand::Status => guild_galleries.iter().map(|watched_gallery| watched_gallery.status()).collect::<Vec<_>>().join("\n"),
+            GalleryCommand::Link => guild_galleries.iter()
+                .map(|watched_gallery| match &watched_gallery.build_target.build_options.public_url {
+                    Some(public_url) => format!("`{}`: {public_url}", watched_gallery.website_name()),
+                    None => format!("`{}` has no public URL set, add `public_url` to its profile or pass `--public-url`", watched_gallery.website_name()),
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    };
+
+    if let Err(err) = command_request.reply(source.http(), &reply).await {
+        eprintln!("Error replying to the gallery command: {err}");
+    }
+}
+
+/// Resyncs and rebuilds galleries one at a time, updating the reply as each one starts
+async fn rebuild_galleries(source: &TwilightSource, guild_galleries: &mut [&mut WatchedGallery], command_request: &GalleryCommandRequest) -> String {
+    let mut results = Vec::new();
+    let gallery_count = guild_galleries.len();
+    for (i, watched_gallery) in guild_galleries.iter_mut().enumerate() {
+        let progress = format!("Rebuilding `{}` ({}/{gallery_count})...", watched_gallery.website_name(), i + 1);
+        // Progress is only nice to have, the final reply is what matters
+        let _ = command_request.reply(source.http(), &progress).await;
+
+        let result = match watched_gallery.resync(source).await {
+            Ok(()) => watched_gallery.rebuild(source).await,
+            Err(err) => Err(err),
+        };
+        results.push(match result {
+            Ok(()) => format!("Rebuilt `{}`", watched_gallery.website_name()),
+            Err(err) => format!("Failed to rebuild `{}`: {err}", watched_gallery.website_name()),
+        });
+    }
+
+    results.join("\n")
+}
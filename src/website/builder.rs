@@ -1,23 +1,16 @@
-use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
-use handlebars::Handlebars;
-use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
+use handlebars::RenderError;
+use serde_derive::Serialize;
 
-use crate::website::builder::gallery_page_info::GalleryPageInfo;
-
-const WEBSITE_RESOURCE_GALLERY_TEMPLATE_HTML: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/website_files/gallery_template.html"));
-
-static HANDLEBARS: Lazy<Handlebars> = Lazy::new(|| {
-    let mut handlebars = Handlebars::new();
-    handlebars.register_template_string("html_template", WEBSITE_RESOURCE_GALLERY_TEMPLATE_HTML).expect("Error registering gallery html template");
-    handlebars
-});
+use crate::website::builder::gallery_page_info::{GalleryPageInfo, GalleryPictureInfo};
+use crate::website::theme::{GALLERY_TEMPLATE_NAME, INDEX_TEMPLATE_NAME, PHOTO_TEMPLATE_NAME, REDIRECT_TEMPLATE_NAME, Theme};
 
 pub mod gallery_page_info {
     use serde_derive::{Deserialize, Serialize};
 
+    use crate::image_metadata::CameraInfo;
+
     #[derive(Serialize, Deserialize)]
     pub struct GalleryPageInfo {
         pub(crate) page_title: String,
@@ -31,21 +24,306 @@ pub mod gallery_page_info {
     #[derive(Serialize, Deserialize)]
     pub struct Gallery {
         pub(crate) gallery_title: String,
+        /// Names the gallery's pages, which stays the same between builds as long as its channel isn't renamed
+        pub(crate) slug: String,
         pub(crate) gallery_picture_infos: Vec<GalleryPictureInfo>,
     }
 
     #[derive(Serialize, Deserialize)]
     pub struct GalleryPictureInfo {
         pub(crate) picture_description: Option<String>,
-        pub(crate) discord_url: String,
+        /// Url of the full size image, either on discord or mirrored into the website
+        pub(crate) full_url: String,
+        /// Url of the image shown on the picture's own page, the mirrored original or else the largest JPEG thumbnail,
+        /// as discord's links expire
+        pub(crate) photo_url: String,
+        /// Url of the smallest JPEG thumbnail, for browsers that don't support `srcset`
         pub(crate) thumbnail_url: String,
+        /// Every size of JPEG thumbnail, smallest first
+        pub(crate) thumbnail_variants: Vec<ThumbnailVariant>,
+        /// Thumbnails in newer formats, offered to browsers with `<source>` before the JPEG ones
+        pub(crate) thumbnail_sources: Vec<ThumbnailSet>,
+        /// Size of `thumbnail_url`, so the page can reserve space for it before it loads
+        pub(crate) thumbnail_width: Option<u32>,
+        pub(crate) thumbnail_height: Option<u32>,
+        /// Shown in place of the thumbnail until it loads
+        pub(crate) placeholder_color: Option<String>,
+        /// How many near-duplicates of the picture were collapsed into it
+        pub(crate) variant_count: usize,
+        /// Own pages of the near-duplicates collapsed into the picture, which redirect to its page
+        pub(crate) collapsed_permalink_urls: Vec<String>,
+        /// Only filled in when the build keeps camera info
+        #[serde(flatten)]
+        pub(crate) camera_info: CameraInfo,
+        pub(crate) author_name: String,
+        /// When the picture's message was posted, in RFC 3339
+        pub(crate) posted_at: String,
+        /// `posted_at` as shown on the page
+        pub(crate) posted_date: String,
+        pub(crate) message_id: String,
+        /// Link to the picture's message on discord
+        pub(crate) message_url: String,
+        /// Url of the picture's own page, relative to the website root
+        pub(crate) permalink_url: String,
+        /// Which attachment the picture is, also used to look up its thumbnails once they're downloaded
+        pub(crate) attachment_id: String,
+        pub(crate) filename: String,
+        /// Dimensions of the full size image in pixels, from discord or else from the downloaded image
+        pub(crate) width: Option<u32>,
+        pub(crate) height: Option<u32>,
+        /// Size of the full size image in bytes
+        pub(crate) size: Option<u64>,
+    }
+
+    /// Every size of thumbnail in a single format
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct ThumbnailSet {
+        pub(crate) mime_type: String,
+        pub(crate) variants: Vec<ThumbnailVariant>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct ThumbnailVariant {
+        pub(crate) url: String,
+        /// In pixels, used as the `w` descriptor of `srcset`
+        pub(crate) width: u32,
     }
 }
 
-pub struct RenderedPage(pub(crate) String);
+/// Gallery pages are written into this folder of the website root, which every build replaces as a whole
+pub const GALLERY_PAGES_FOLDER: &str = "galleries";
+/// Pages of single pictures are written into this folder of the website root, which every build replaces as a whole
+pub const PHOTO_PAGES_FOLDER: &str = "photos";
+const INDEX_PAGE_PATH: &str = "index.html";
+/// Leads from a gallery page back to the website root, used as its `<base href>` so it can link to everything the
+/// same way the index does
+const GALLERY_PAGE_ROOT_PATH: &str = "../../";
+const PHOTO_PAGE_ROOT_PATH: &str = "../";
+
+/// A page of the website, with its path relative to the website root
+pub struct RenderedPage {
+    pub(crate) path: PathBuf,
+    pub(crate) html: String,
+}
+
+#[derive(Serialize)]
+struct IndexPageData<'a> {
+    page_title: &'a str,
+    galleries: Vec<GallerySummary<'a>>,
+    guild_built_from: &'a str,
+    page_built_time: &'a str,
+}
+
+#[derive(Serialize)]
+struct GallerySummary<'a> {
+    gallery_title: &'a str,
+    url: String,
+    picture_count: usize,
+    /// The first picture of the gallery
+    cover: Option<&'a GalleryPictureInfo>,
+}
+
+#[derive(Serialize)]
+struct GalleryPageData<'a> {
+    page_title: &'a str,
+    gallery_title: &'a str,
+    /// Just the gallery being shown with just this page's pictures, so templates for the single page site still work
+    galleries: [GalleryPagePictures<'a>; 1],
+    pagination: Pagination,
+    root_path: &'static str,
+    index_url: &'static str,
+    guild_built_from: &'a str,
+    page_built_time: &'a str,
+}
+
+#[derive(Serialize)]
+struct GalleryPagePictures<'a> {
+    gallery_title: &'a str,
+    gallery_picture_infos: &'a [GalleryPictureInfo],
+}
+
+#[derive(Serialize)]
+struct PhotoPageData<'a> {
+    page_title: &'a str,
+    gallery_title: &'a str,
+    picture: &'a GalleryPictureInfo,
+    /// Page of the gallery the picture is on
+    gallery_url: String,
+    previous_url: Option<&'a str>,
+    next_url: Option<&'a str>,
+    open_graph: OpenGraph,
+    root_path: &'static str,
+    index_url: &'static str,
+    guild_built_from: &'a str,
+    page_built_time: &'a str,
+}
+
+/// Stands in for the page of a picture that was collapsed into a near-duplicate, so links to it keep working
+#[derive(Serialize)]
+struct RedirectPageData {
+    /// Relative to the page being redirected from
+    target_url: String,
+}
 
-pub fn render_page(gallery_page_info: &GalleryPageInfo) -> RenderedPage {
-    let built_html = HANDLEBARS.render("html_template", &gallery_page_info).expect("Failed to render gallery page info");
+/// What a link to a page unfurls into on discord and other sites, whose urls have to be absolute to work
+#[derive(Serialize)]
+struct OpenGraph {
+    title: String,
+    description: Option<String>,
+    image: Option<String>,
+    url: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Pagination {
+    page_number: usize,
+    page_count: usize,
+    previous_url: Option<String>,
+    next_url: Option<String>,
+    pages: Vec<PageLink>,
+}
 
-    RenderedPage(built_html)
-}
\ No newline at end of file
+#[derive(Serialize)]
+struct PageLink {
+    page_number: usize,
+    url: String,
+    is_current: bool,
+}
+
+/// Renders the index page, every page of every gallery, putting up to `pictures_per_page` pictures on each, and a page
+/// for every picture. `public_url` is where the website is published, if known.
+pub fn render_site(theme: &Theme, gallery_page_info: &GalleryPageInfo, pictures_per_page: usize, public_url: Option<&str>) -> Result<Vec<RenderedPage>, RenderError> {
+    let GalleryPageInfo { page_title, galleries, guild_built_from, page_built_time } = gallery_page_info;
+    let pictures_per_page = pictures_per_page.max(1);
+
+    let index_page_data = IndexPageData {
+        page_title,
+        galleries: galleries
+            .iter()
+            .map(|gallery| GallerySummary {
+                gallery_title: &gallery.gallery_title,
+                url: gallery_page_url(&gallery.slug, 1),
+                picture_count: gallery.gallery_picture_infos.len(),
+                cover: gallery.gallery_picture_infos.first(),
+            })
+            .collect(),
+        guild_built_from,
+        page_built_time,
+    };
+    let mut rendered_pages = vec![RenderedPage {
+        path: PathBuf::from(INDEX_PAGE_PATH),
+        html: theme.handlebars.render(INDEX_TEMPLATE_NAME, &index_page_data)?,
+    }];
+
+    for gallery in galleries {
+        let page_count = gallery.gallery_picture_infos.len().div_ceil(pictures_per_page).max(1);
+        for page_number in 1..=page_count {
+            let page_pictures = gallery.gallery_picture_infos.chunks(pictures_per_page).nth(page_number - 1).unwrap_or_default();
+            let gallery_page_data = GalleryPageData {
+                page_title,
+                gallery_title: &gallery.gallery_title,
+                galleries: [GalleryPagePictures {
+                    gallery_title: &gallery.gallery_title,
+                    gallery_picture_infos: page_pictures,
+                }],
+                pagination: Pagination {
+                    page_number,
+                    page_count,
+                    previous_url: (page_number > 1).then(|| gallery_page_url(&gallery.slug, page_number - 1)),
+                    next_url: (page_number < page_count).then(|| gallery_page_url(&gallery.slug, page_number + 1)),
+                    pages: (1..=page_count)
+                        .map(|linked_page_number| PageLink {
+                            page_number: linked_page_number,
+                            url: gallery_page_url(&gallery.slug, linked_page_number),
+                            is_current: linked_page_number == page_number,
+                        })
+                        .collect(),
+                },
+                root_path: GALLERY_PAGE_ROOT_PATH,
+                index_url: INDEX_PAGE_PATH,
+                guild_built_from,
+                page_built_time,
+            };
+
+            rendered_pages.push(RenderedPage {
+                path: PathBuf::from(gallery_page_url(&gallery.slug, page_number)),
+                html: theme.handlebars.render(GALLERY_TEMPLATE_NAME, &gallery_page_data)?,
+            });
+        }
+
+        let pictures = &gallery.gallery_picture_infos;
+        for (picture_index, picture) in pictures.iter().enumerate() {
+            let photo_page_data = PhotoPageData {
+                page_title,
+                gallery_title: &gallery.gallery_title,
+                picture,
+                gallery_url: gallery_page_url(&gallery.slug, picture_index / pictures_per_page + 1),
+                previous_url: picture_index.checked_sub(1).map(|previous_index| pictures[previous_index].permalink_url.as_str()),
+                next_url: pictures.get(picture_index + 1).map(|next_picture| next_picture.permalink_url.as_str()),
+                open_graph: OpenGraph {
+                    title: format!("{} | {}", gallery.gallery_title, page_title),
+                    description: picture.picture_description.clone(),
+                    image: picture.thumbnail_variants.last().and_then(|variant| absolute_url(&variant.url, public_url)),
+                    url: absolute_url(&picture.permalink_url, public_url),
+                },
+                root_path: PHOTO_PAGE_ROOT_PATH,
+                index_url: INDEX_PAGE_PATH,
+                guild_built_from,
+                page_built_time,
+            };
+
+            rendered_pages.push(RenderedPage {
+                path: PathBuf::from(&picture.permalink_url),
+                html: theme.handlebars.render(PHOTO_TEMPLATE_NAME, &photo_page_data)?,
+            });
+            for collapsed_permalink_url in &picture.collapsed_permalink_urls {
+                rendered_pages.push(RenderedPage {
+                    path: PathBuf::from(collapsed_permalink_url),
+                    html: theme.handlebars.render(REDIRECT_TEMPLATE_NAME, &RedirectPageData {
+                        target_url: format!("{PHOTO_PAGE_ROOT_PATH}{}", picture.permalink_url),
+                    })?,
+                });
+            }
+        }
+    }
+
+    Ok(rendered_pages)
+}
+
+/// Url of a page of a gallery relative to the website root, which is also where it's written
+fn gallery_page_url(slug: &str, page_number: usize) -> String {
+    if page_number == 1 {
+        format!("{GALLERY_PAGES_FOLDER}/{slug}/index.html")
+    } else {
+        format!("{GALLERY_PAGES_FOLDER}/{slug}/page-{page_number}.html")
+    }
+}
+
+/// Url of a picture's own page relative to the website root, which is also where it's written. Attachment ids don't
+/// change, so links to it keep working as long as the picture is in a gallery.
+pub fn photo_page_url(attachment_id: &str) -> String {
+    format!("{PHOTO_PAGES_FOLDER}/{attachment_id}.html")
+}
+
+/// Makes a url relative to the website root absolute, which is only possible when it's known where it's published
+fn absolute_url(url: &str, public_url: Option<&str>) -> Option<String> {
+    if url.contains("://") {
+        return Some(url.to_owned());
+    }
+
+    public_url.map(|public_url| format!("{}/{}", public_url.trim_end_matches('/'), url))
+}
+
+/// Turns a channel name into the part of its gallery's url, which is empty if nothing of the name is url safe
+pub fn slugify(name: &str) -> String {
+    let mut slug = String::new();
+    for character in name.chars() {
+        if character.is_ascii_alphanumeric() {
+            slug.push(character.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    slug.trim_end_matches('-').to_owned()
+}
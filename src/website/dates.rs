@@ -0,0 +1,114 @@
+//! When a website was built, and the Handlebars helpers for showing dates relative to that, all in the timezone the
+//! website is built for:
+//!
+//! ```text
+//! {{format_date posted_at "%d.%m.%Y"}}   an RFC 3339 date in a strftime style format, or the build's time format without one
+//! {{relative_date posted_at}}            how long before the build it was, like `3 days ago`
+//! ```
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use handlebars::{Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError, RenderErrorReason};
+
+pub const DEFAULT_TIME_FORMAT: &str = "%B %-d, %Y at %H:%M %Z";
+
+#[derive(Debug, Clone)]
+pub struct BuildTime {
+    built_at: DateTime<Utc>,
+    timezone: Tz,
+    time_format: String,
+}
+
+impl BuildTime {
+    pub fn now(timezone: Tz, time_format: &str) -> Result<BuildTime, String> {
+        check_time_format(time_format)?;
+
+        Ok(BuildTime {
+            built_at: Utc::now(),
+            timezone,
+            time_format: time_format.to_owned(),
+        })
+    }
+
+    /// The build time in the build's timezone and time format
+    pub fn formatted(&self) -> String {
+        self.format(self.built_at, &self.time_format)
+    }
+
+    pub fn format(&self, date: DateTime<Utc>, time_format: &str) -> String {
+        date.with_timezone(&self.timezone).format(time_format).to_string()
+    }
+
+    pub fn register_helpers(&self, handlebars: &mut Handlebars) {
+        handlebars.register_helper("format_date", Box::new(FormatDateHelper(self.clone())));
+        handlebars.register_helper("relative_date", Box::new(RelativeDateHelper(self.clone())));
+    }
+}
+
+/// Checks a strftime style format up front, as chrono panics when formatting with an invalid one
+pub fn check_time_format(time_format: &str) -> Result<(), String> {
+    if StrftimeItems::new(time_format).any(|item| item == Item::Error) {
+        return Err(format!("Invalid time format `{time_format}`"));
+    }
+
+    Ok(())
+}
+
+struct FormatDateHelper(BuildTime);
+
+impl HelperDef for FormatDateHelper {
+    fn call<'reg: 'rc, 'rc>(&self, h: &Helper<'rc>, _: &'reg Handlebars<'reg>, _: &'rc Context, _: &mut RenderContext<'reg, 'rc>, out: &mut dyn Output) -> HelperResult {
+        let Some(date) = date_param(h)? else {
+            return Ok(());
+        };
+        let time_format = match h.param(1) {
+            Some(time_format) => time_format.value().as_str().ok_or(RenderErrorReason::InvalidParamType("time format string"))?,
+            None => &self.0.time_format,
+        };
+        check_time_format(time_format).map_err(RenderErrorReason::Other)?;
+
+        out.write(&self.0.format(date, time_format))?;
+        Ok(())
+    }
+}
+
+struct RelativeDateHelper(BuildTime);
+
+impl HelperDef for RelativeDateHelper {
+    fn call<'reg: 'rc, 'rc>(&self, h: &Helper<'rc>, _: &'reg Handlebars<'reg>, _: &'rc Context, _: &mut RenderContext<'reg, 'rc>, out: &mut dyn Output) -> HelperResult {
+        let Some(date) = date_param(h)? else {
+            return Ok(());
+        };
+
+        out.write(&describe_age(self.0.built_at - date))?;
+        Ok(())
+    }
+}
+
+/// The date given as the first parameter, nothing if it's missing so optional fields can be passed as is
+fn date_param(h: &Helper) -> Result<Option<DateTime<Utc>>, RenderError> {
+    let Some(date) = h.param(0).and_then(|date| date.value().as_str()) else {
+        return Ok(None);
+    };
+    if date.is_empty() {
+        return Ok(None);
+    }
+
+    let date = DateTime::parse_from_rfc3339(date)
+        .map_err(|err| RenderErrorReason::Other(format!("`{}` can't be used with `{}`, it isn't an RFC 3339 date: {}", date, h.name(), err)))?;
+    Ok(Some(date.with_timezone(&Utc)))
+}
+
+fn describe_age(age: Duration) -> String {
+    let count_ago = |count: i64, unit: &str| format!("{} {}{} ago", count, unit, if count == 1 { "" } else { "s" });
+
+    match age.num_days() {
+        days if days >= 365 => count_ago(days / 365, "year"),
+        days if days >= 30 => count_ago(days / 30, "month"),
+        days if days >= 1 => count_ago(days, "day"),
+        _ if age.num_hours() >= 1 => count_ago(age.num_hours(), "hour"),
+        _ if age.num_minutes() >= 1 => count_ago(age.num_minutes(), "minute"),
+        _ => "just now".to_owned(),
+    }
+}
@@ -1,20 +1,38 @@
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 
-use crate::website::builder::RenderedPage;
+use crate::website::builder::{GALLERY_PAGES_FOLDER, PHOTO_PAGES_FOLDER, RenderedPage};
+use crate::website::theme::{RESOURCES_FOLDER, Theme};
 
 pub mod builder;
+pub mod dates;
+pub mod publish;
+pub mod theme;
 
-const WEBSITE_RESOURCE_GALLERY_JS_SRC: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/website_files/resources/gallery.js"));
-const WEBSITE_RESOURCE_GALLERY_STYLES_CSS_SRC: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/website_files/resources/gallery-style.css"));
-
-pub fn write_whole_website_directory<P: AsRef<Path>>(path: P, rendered_page: &RenderedPage) {
+pub fn write_whole_website_directory<P: AsRef<Path>>(path: P, rendered_pages: &[RenderedPage], theme: &Theme) -> io::Result<()> {
     let website_folder_path = PathBuf::from(path.as_ref());
-    let website_resources_path = website_folder_path.join("resources");
+    let website_resources_path = website_folder_path.join(RESOURCES_FOLDER);
+
+    fs::create_dir_all(&website_folder_path)?;
+    for (resource_path, contents) in &theme.resources {
+        let resource_save_path = website_resources_path.join(resource_path);
+        fs::create_dir_all(resource_save_path.parent().unwrap())?;
+        fs::write(resource_save_path, contents)?;
+    }
+
+    // Pages of galleries and pictures that were removed would otherwise linger
+    for pages_folder in [GALLERY_PAGES_FOLDER, PHOTO_PAGES_FOLDER] {
+        let pages_path = website_folder_path.join(pages_folder);
+        if pages_path.exists() {
+            fs::remove_dir_all(&pages_path)?;
+        }
+    }
+    for rendered_page in rendered_pages {
+        let page_save_path = website_folder_path.join(&rendered_page.path);
+        fs::create_dir_all(page_save_path.parent().unwrap())?;
+        fs::write(page_save_path, &rendered_page.html)?;
+    }
 
-    fs::create_dir_all(&website_folder_path).expect("Failed to create website folder");
-    fs::create_dir_all(&website_resources_path).expect("Failed to create website resources folder");
-    fs::write(website_resources_path.join("gallery.js"), WEBSITE_RESOURCE_GALLERY_JS_SRC).unwrap();
-    fs::write(website_resources_path.join("gallery-style.css"), WEBSITE_RESOURCE_GALLERY_STYLES_CSS_SRC).unwrap();
-    fs::write(website_folder_path.join("index.html"), &rendered_page.0).unwrap();
-}
\ No newline at end of file
+    Ok(())
+}
@@ -0,0 +1,256 @@
+//! Builds are written into a staging copy of the website, which only replaces the live website once it's complete, so
+//! nobody visiting mid-build sees a page pointing at thumbnails that aren't there yet.
+//!
+//! On Linux the staging copy and the live website are swapped in one step. Elsewhere they're swapped by renaming, which
+//! leaves the website missing for the moment between two renames.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::prune::DOWNLOADED_IMAGE_FOLDERS;
+use crate::thumbnail_download::THUMBNAIL_MANIFEST_FILE_NAME;
+
+/// Appended to the website root's folder name for the copy a build is written into
+const STAGING_SUFFIX: &str = ".staging";
+/// Appended to the website root's folder name for the build before the live one, kept to roll back to
+const PREVIOUS_SUFFIX: &str = ".previous";
+/// Appended to the website root's folder name for the folder a swap moves it aside into, where it can't be done in one
+/// step
+const SWAP_SUFFIX: &str = ".swap";
+/// Left in a staging copy that was held back, so the next build knows its downloads are complete and can keep them
+const HELD_BACK_MARKER: &str = ".held_back";
+
+/// A staging copy of a website, see [`StagedWebsite::publish`]. It's removed if the build fails or panics before
+/// publishing it or holding it back.
+#[derive(Debug)]
+pub struct StagedWebsite {
+    live_path: PathBuf,
+    staging_path: PathBuf,
+    /// Whether the staging copy was published or held back, either way it isn't removed
+    finished: bool,
+}
+
+impl StagedWebsite {
+    /// Starts a staging copy of the live website at `website_root`, replacing any left behind by a failed build but
+    /// keeping the downloads of a held back one. Downloaded images are hard linked instead of copied, as they are never
+    /// changed once saved.
+    pub fn prepare(website_root: &Path) -> io::Result<StagedWebsite> {
+        let website_root = resolve_website_root(website_root)?;
+        let staging_path = sibling_path(&website_root, STAGING_SUFFIX)?;
+        if staging_path.join(HELD_BACK_MARKER).is_file() {
+            println!("Starting from the downloads of held back `{}`", staging_path.display());
+            remove_all_but_downloads(&staging_path)?;
+        } else if staging_path.exists() {
+            fs::remove_dir_all(&staging_path)?;
+        }
+
+        fs::create_dir_all(&staging_path)?;
+        if website_root.is_dir() {
+            copy_website(&website_root, &staging_path)?;
+        }
+
+        Ok(StagedWebsite {
+            live_path: website_root,
+            staging_path,
+            finished: false,
+        })
+    }
+
+    /// Where the build should be written
+    pub fn path(&self) -> &Path {
+        &self.staging_path
+    }
+
+    /// Swaps the staging copy in for the live website, keeping the live one to roll back to
+    pub fn publish(mut self) -> io::Result<()> {
+        let previous_path = sibling_path(&self.live_path, PREVIOUS_SUFFIX)?;
+        if previous_path.exists() {
+            fs::remove_dir_all(&previous_path)?;
+        }
+
+        if self.live_path.exists() {
+            // The live website ends up where the staging copy was, from where it becomes the previous build
+            swap_folders(&self.staging_path, &self.live_path)?;
+            self.finished = true;
+            fs::rename(&self.staging_path, &previous_path)?;
+            println!("Published `{}`, the build before it is kept in `{}`", self.live_path.display(), previous_path.display());
+        } else {
+            fs::rename(&self.staging_path, &self.live_path)?;
+            self.finished = true;
+            println!("Published `{}`", self.live_path.display());
+        }
+
+        Ok(())
+    }
+
+    /// Leaves the staging copy unpublished, keeping what it downloaded for the next build to start from
+    pub fn hold_back(mut self) -> io::Result<()> {
+        fs::write(self.staging_path.join(HELD_BACK_MARKER), "")?;
+        self.finished = true;
+        println!("Keeping the downloads of unpublished `{}` for the next build", self.staging_path.display());
+
+        Ok(())
+    }
+}
+
+impl Drop for StagedWebsite {
+    fn drop(&mut self) {
+        if self.finished || !self.staging_path.exists() {
+            return;
+        }
+
+        println!("Removing unpublished `{}`", self.staging_path.display());
+        if let Err(err) = fs::remove_dir_all(&self.staging_path) {
+            eprintln!("Error removing unpublished `{}`: {}", self.staging_path.display(), err);
+        }
+    }
+}
+
+/// Swaps the live website with the build before it, which can be undone by rolling back again
+pub fn rollback_website(website_root: &Path) -> io::Result<()> {
+    let website_root = &resolve_website_root(website_root)?;
+    let previous_path = sibling_path(website_root, PREVIOUS_SUFFIX)?;
+    if !previous_path.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No previous build of `{}` to roll back to", website_root.display())));
+    }
+
+    if website_root.exists() {
+        swap_folders(&previous_path, website_root)?;
+    } else {
+        fs::rename(&previous_path, website_root)?;
+    }
+    println!("Rolled `{}` back to the build before it", website_root.display());
+
+    Ok(())
+}
+
+/// Turns a website root into an absolute path ending in its folder name, which its sibling folders are named after.
+///
+/// Websites are published by renaming their folder, so the working directory can't be in it: it would be renamed out
+/// from under every relative path, and everything else in it, such as the config file, would be published too.
+pub fn resolve_website_root(website_root: &Path) -> io::Result<PathBuf> {
+    let website_root = if website_root.exists() {
+        website_root.canonicalize()?
+    } else {
+        std::path::absolute(website_root)?
+    };
+    if website_root.file_name().is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Website root `{}` needs a folder name to be published", website_root.display())));
+    }
+    if env::current_dir()?.canonicalize()?.starts_with(&website_root) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Website root `{}` can't be the working directory or hold it, build into a folder of its own instead", website_root.display()),
+        ));
+    }
+
+    Ok(website_root)
+}
+
+/// A folder next to the website root with `suffix` appended to its name, so renames between them stay on one filesystem
+fn sibling_path(website_root: &Path, suffix: &str) -> io::Result<PathBuf> {
+    let website_root = resolve_website_root(website_root)?;
+    let folder_name = website_root.file_name().unwrap_or_default();
+
+    let mut sibling_name = folder_name.to_os_string();
+    sibling_name.push(suffix);
+
+    Ok(website_root.with_file_name(sibling_name))
+}
+
+/// Swaps the contents of two folders next to each other, in one step where the filesystem supports it
+#[cfg(target_os = "linux")]
+fn swap_folders(first_path: &Path, second_path: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let first_c_path = CString::new(first_path.as_os_str().as_bytes())?;
+    let second_c_path = CString::new(second_path.as_os_str().as_bytes())?;
+    // SAFETY: both paths are nul terminated and outlive the call
+    let result = unsafe {
+        libc::renameat2(libc::AT_FDCWD, first_c_path.as_ptr(), libc::AT_FDCWD, second_c_path.as_ptr(), libc::RENAME_EXCHANGE)
+    };
+    if result == 0 {
+        return Ok(());
+    }
+
+    // Older kernels and some filesystems can't exchange folders
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EINVAL | libc::ENOSYS) => swap_folders_by_renaming(first_path, second_path),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn swap_folders(first_path: &Path, second_path: &Path) -> io::Result<()> {
+    swap_folders_by_renaming(first_path, second_path)
+}
+
+/// Swaps two folders through a third, leaving nothing at `second_path` for the moment between the first two renames
+fn swap_folders_by_renaming(first_path: &Path, second_path: &Path) -> io::Result<()> {
+    let swap_path = sibling_path(second_path, SWAP_SUFFIX)?;
+    if swap_path.exists() {
+        fs::remove_dir_all(&swap_path)?;
+    }
+
+    fs::rename(second_path, &swap_path)?;
+    fs::rename(first_path, second_path)?;
+    fs::rename(&swap_path, first_path)
+}
+
+/// Removes everything from a held back staging copy except its downloaded images and the manifest of them, which is
+/// newer than the live website's
+fn remove_all_but_downloads(staging_path: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(staging_path)? {
+        let entry = entry?;
+        let is_download = DOWNLOADED_IMAGE_FOLDERS.iter().any(|folder| entry.file_name() == *folder) || entry.file_name() == THUMBNAIL_MANIFEST_FILE_NAME;
+        if is_download {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the live website into the staging folder, hard linking the files of the downloaded image folders. Files
+/// already in the staging folder were kept from a held back build and are left as they are.
+fn copy_website(live_path: &Path, staging_path: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(live_path)? {
+        let entry = entry?;
+        let link_files = DOWNLOADED_IMAGE_FOLDERS.iter().any(|folder| entry.file_name() == *folder);
+        copy_entry(&entry, &staging_path.join(entry.file_name()), link_files)?;
+    }
+
+    Ok(())
+}
+
+fn copy_entry(entry: &fs::DirEntry, to_path: &Path, link_files: bool) -> io::Result<()> {
+    let from_path = entry.path();
+    if entry.file_type()?.is_dir() {
+        fs::create_dir_all(to_path)?;
+        for child_entry in fs::read_dir(&from_path)? {
+            let child_entry = child_entry?;
+            copy_entry(&child_entry, &to_path.join(child_entry.file_name()), link_files)?;
+        }
+    } else if to_path.exists() {
+        return Ok(());
+    } else if link_files {
+        // Linking fails on some filesystems, copying is slower but always works
+        if fs::hard_link(&from_path, to_path).is_err() {
+            fs::copy(&from_path, to_path)?;
+        }
+    } else {
+        fs::copy(&from_path, to_path)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,137 @@
+//! The templates and static assets a website is built from. A theme folder given at runtime can replace any of the
+//! built-in ones and add its own:
+//!
+//! ```text
+//! my_theme/
+//!     index_template.html        the index page template
+//!     gallery_template.html      the template of every page of a gallery
+//!     photo_template.html        the template of every picture's own page
+//!     redirect_template.html     the template of pages that send visitors on to another page
+//!     partials/gallery_picture.hbs   partials, used as `{{> gallery_picture}}`
+//!     resources/gallery-style.css    static assets, copied into the website's `resources` folder
+//! ```
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use handlebars::Handlebars;
+
+/// Name the index page template is registered under
+pub const INDEX_TEMPLATE_NAME: &str = "index_template";
+/// Name the gallery page template is registered under
+pub const GALLERY_TEMPLATE_NAME: &str = "gallery_template";
+/// Name the picture page template is registered under
+pub const PHOTO_TEMPLATE_NAME: &str = "photo_template";
+/// Name the redirect page template is registered under
+pub const REDIRECT_TEMPLATE_NAME: &str = "redirect_template";
+
+/// Page templates by file name in the theme folder
+const TEMPLATE_FILE_NAMES: [(&str, &str); 4] = [
+    ("index_template.html", INDEX_TEMPLATE_NAME),
+    ("gallery_template.html", GALLERY_TEMPLATE_NAME),
+    ("photo_template.html", PHOTO_TEMPLATE_NAME),
+    ("redirect_template.html", REDIRECT_TEMPLATE_NAME),
+];
+const PARTIALS_FOLDER: &str = "partials";
+const PARTIAL_EXTENSION: &str = "hbs";
+/// Folder of the theme copied into the website as is
+pub const RESOURCES_FOLDER: &str = "resources";
+
+/// Every file of the built-in theme, by path relative to the theme folder
+const BUILT_IN_THEME_FILES: [(&str, &str); 8] = [
+    ("index_template.html", include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/website_files/index_template.html"))),
+    ("gallery_template.html", include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/website_files/gallery_template.html"))),
+    ("photo_template.html", include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/website_files/photo_template.html"))),
+    ("redirect_template.html", include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/website_files/redirect_template.html"))),
+    ("partials/gallery_picture.hbs", include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/website_files/partials/gallery_picture.hbs"))),
+    ("partials/pagination.hbs", include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/website_files/partials/pagination.hbs"))),
+    ("resources/gallery.js", include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/website_files/resources/gallery.js"))),
+    ("resources/gallery-style.css", include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/website_files/resources/gallery-style.css"))),
+];
+
+pub struct Theme {
+    pub(crate) handlebars: Handlebars<'static>,
+    /// Static assets by path relative to the website's resources folder
+    pub(crate) resources: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl Theme {
+    /// Loads the built-in theme with the files of `theme_folder` in place of its own, if one is given
+    pub fn load(theme_folder: Option<&Path>) -> Result<Theme, Box<dyn Error + Send + Sync>> {
+        let mut theme_files = BUILT_IN_THEME_FILES
+            .iter()
+            .map(|&(path, contents)| (PathBuf::from(path), contents.as_bytes().to_vec()))
+            .collect::<BTreeMap<_, _>>();
+        if let Some(theme_folder) = theme_folder {
+            if !theme_folder.is_dir() {
+                return Err(format!("Theme folder `{}` doesn't exist", theme_folder.display()).into());
+            }
+            read_theme_folder(theme_folder, theme_folder, &mut theme_files)?;
+        }
+
+        let mut handlebars = Handlebars::new();
+        let mut resources = BTreeMap::new();
+        for (path, contents) in theme_files {
+            let describe_path = || match theme_folder {
+                Some(theme_folder) => theme_folder.join(&path).display().to_string(),
+                None => format!("built-in {}", path.display()),
+            };
+
+            if let Some(&(_, template_name)) = TEMPLATE_FILE_NAMES.iter().find(|&&(file_name, _)| path == Path::new(file_name)) {
+                let template = String::from_utf8(contents).map_err(|_| format!("Template `{}` isn't UTF-8", describe_path()))?;
+                handlebars.register_template_string(template_name, template)
+                    .map_err(|err| format!("Failed to parse template `{}`: {}", describe_path(), err))?;
+            } else if let Ok(partial_path) = path.strip_prefix(PARTIALS_FOLDER) {
+                if partial_path.extension().is_some_and(|extension| extension == PARTIAL_EXTENSION) {
+                    let partial_name = partial_path.with_extension("").to_string_lossy().replace('\\', "/");
+                    let partial = String::from_utf8(contents).map_err(|_| format!("Partial `{}` isn't UTF-8", describe_path()))?;
+                    handlebars.register_partial(&partial_name, partial)
+                        .map_err(|err| format!("Failed to parse partial `{}`: {}", describe_path(), err))?;
+                }
+            } else if let Ok(resource_path) = path.strip_prefix(RESOURCES_FOLDER) {
+                resources.insert(resource_path.to_path_buf(), contents);
+            }
+        }
+
+        Ok(Theme {
+            handlebars,
+            resources,
+        })
+    }
+}
+
+/// Writes the built-in theme into `theme_folder`, as a starting point for a theme of its own. Files that are already
+/// there are left alone.
+pub fn export_built_in_theme(theme_folder: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for (path, contents) in BUILT_IN_THEME_FILES {
+        let export_path = theme_folder.join(path);
+        if export_path.exists() {
+            println!("Skipping `{}`, it already exists", export_path.display());
+            continue;
+        }
+
+        fs::create_dir_all(export_path.parent().unwrap())?;
+        fs::write(&export_path, contents)?;
+        println!("Exported `{}`", export_path.display());
+    }
+
+    Ok(())
+}
+
+/// Reads every file under `folder` into `theme_files`, replacing built-in files with the same path
+fn read_theme_folder(theme_folder: &Path, folder: &Path, theme_files: &mut BTreeMap<PathBuf, Vec<u8>>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for entry in fs::read_dir(folder)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            read_theme_folder(theme_folder, &path, theme_files)?;
+        } else {
+            let contents = fs::read(&path).map_err(|err| format!("Failed to read theme file `{}`: {}", path.display(), err))?;
+            theme_files.insert(path.strip_prefix(theme_folder).unwrap().to_path_buf(), contents);
+        }
+    }
+
+    Ok(())
+}
@@ -16,6 +16,9 @@ function showPreview(gimp) {
         let previewImg = new Image();
         previewImg.src = gimp.dataset.fullurl;
         previewDiv.appendChild(previewImg);
+        showVariantsBadge(previewDiv, gimp);
+        showCameraCaption(previewDiv, gimp);
+        showPermalink(previewDiv, gimp);
         previewDiv.style.display = "block";
     } else {
         previewDiv.style.display = "none";
@@ -24,6 +27,50 @@ function showPreview(gimp) {
 
 }
 
+function showVariantsBadge(previewDiv, gimp) {
+    if (!gimp.dataset.variants) {
+        return;
+    }
+
+    let variantsBadge = document.createElement("div");
+    variantsBadge.className = "variants-badge";
+    const variantCount = parseInt(gimp.dataset.variants);
+    variantsBadge.innerText = "+" + variantCount + (variantCount === 1 ? " variant" : " variants");
+    variantsBadge.title = "Similar versions of this photo were also posted";
+    previewDiv.appendChild(variantsBadge);
+}
+
+function showCameraCaption(previewDiv, gimp) {
+    const captionLines = [
+        gimp.dataset.camera,
+        gimp.dataset.lens,
+        gimp.dataset.exposure,
+        gimp.dataset.taken && ("Taken " + gimp.dataset.taken),
+    ].filter(line => line);
+    if (captionLines.length === 0) {
+        return;
+    }
+
+    let caption = document.createElement("div");
+    caption.className = "camera-caption";
+    captionLines.forEach(line => {
+        let captionLine = document.createElement("div");
+        captionLine.innerText = line;
+        caption.appendChild(captionLine);
+    });
+    previewDiv.appendChild(caption);
+}
+
+function showPermalink(previewDiv, gimp) {
+    let permalink = document.createElement("a");
+    permalink.className = "permalink";
+    permalink.href = gimp.dataset.permalink;
+    permalink.innerText = "Link to this photo";
+    // Following the link shouldn't also close the preview
+    permalink.addEventListener("click", event => event.stopPropagation());
+    previewDiv.appendChild(permalink);
+}
+
 function setupGallery() {
     const allGalleries = document.querySelectorAll(".gallery");
     const allGalleryImages = document.querySelectorAll(".gallery img");
@@ -36,16 +83,18 @@ function setupGallery() {
 
     const onGalleryImageLoaded = function (gimg) {
         gimg.style.display = "inline";
-        gimg.addEventListener("click", function (_event) {
-            showPreview(gimg);
-        });
         resizeAllGalleries();
     }
 
     allGalleryImages.forEach(gimg => {
-        if (gimg.complete) {
-            onGalleryImageLoaded(gimg);
-        } else {
+        gimg.addEventListener("click", function (_event) {
+            showPreview(gimg);
+        });
+        // Images with a reserved size can be laid out with their placeholder before they arrive
+        if (gimg.complete || gimg.hasAttribute("width")) {
+            gimg.style.display = "inline";
+        }
+        if (!gimg.complete) {
             gimg.addEventListener("load", function (_event) {
                 onGalleryImageLoaded(gimg);
             });
@@ -55,6 +104,7 @@ function setupGallery() {
         }
 
     });
+    resizeAllGalleries();
 
     window.addEventListener("resize", function (_event) {
         resizeAllGalleries();